@@ -0,0 +1,650 @@
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::HttpError;
+use crate::openai_payload::{generate_endpoint, Message, OpenAIPayload, OpenAIResponse};
+
+#[cfg(not(test))]
+use crate::helpers::body::{IntoBody, Multipart, MultipartPart};
+
+pub(crate) const DEFAULT_MAX_BATCH_REQUESTS: usize = 1000;
+const FILES_ENDPOINT: &str = "/v1/files";
+const BATCHES_ENDPOINT: &str = "/v1/batches";
+// every line of the input file is a chat completion
+const BATCH_URL: &str = "/v1/chat/completions";
+const COMPLETION_WINDOW: &str = "24h";
+const MAX_CUSTOM_ID_LENGTH: usize = 64;
+const MAX_ID_LENGTH: usize = 128;
+// the metadata tagging the batches this component created, the only ones it exposes
+const BATCH_TAG_KEY: &str = "created_by";
+const BATCH_TAG_VALUE: &str = "edgee-openai-component";
+// the custom_ids of the requests refused by the input blocklist, as a JSON array
+const BATCH_REFUSED_KEY: &str = "refused";
+const MAX_METADATA_VALUE_LENGTH: usize = 512;
+
+#[derive(serde::Serialize)]
+struct BatchInputLine<'a> {
+    custom_id: &'a str,
+    method: &'static str,
+    url: &'static str,
+    body: &'a OpenAIPayload,
+}
+
+/*
+ * Encodes the chat payloads as the JSONL input file of a batch,
+ * one `POST /v1/chat/completions` request per line.
+ */
+pub(crate) fn encode_jsonl(requests: &[(String, OpenAIPayload)]) -> Result<String, anyhow::Error> {
+    let mut jsonl = String::new();
+    for (custom_id, payload) in requests {
+        jsonl.push_str(&serde_json::to_string(&BatchInputLine {
+            custom_id,
+            method: "POST",
+            url: BATCH_URL,
+            body: payload,
+        })?);
+        jsonl.push('\n');
+    }
+    Ok(jsonl)
+}
+
+#[derive(serde::Deserialize)]
+struct BatchErrorBody {
+    #[serde(default)]
+    message: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct BatchOutputResponse {
+    status_code: u16,
+    #[serde(default)]
+    body: serde_json::Value,
+}
+
+#[derive(serde::Deserialize)]
+struct BatchOutputLine {
+    custom_id: String,
+    #[serde(default)]
+    response: Option<BatchOutputResponse>,
+    #[serde(default)]
+    error: Option<BatchErrorBody>,
+}
+
+// the outcome of one request of a batch, the reply or why there's none
+#[derive(serde::Serialize)]
+pub(crate) struct BatchResult {
+    pub(crate) custom_id: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub(crate) message: Option<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+}
+
+/*
+ * Decodes the JSONL output or error file of a batch, mapping every line
+ * to the first choice's message, or to the error of the failed request.
+ */
+pub(crate) fn decode_jsonl(jsonl: &str) -> Result<Vec<BatchResult>, anyhow::Error> {
+    jsonl
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(index, line)| {
+            let line: BatchOutputLine = serde_json::from_str(line)
+                .map_err(|e| anyhow::anyhow!("Could not parse batch result {index}: {e}"))?;
+            let outcome = match (line.response, line.error) {
+                (_, Some(error)) => Err(error.message.unwrap_or_default()),
+                (Some(response), None) if (200..300).contains(&response.status_code) => {
+                    OpenAIResponse::from_json_string(response.body.to_string())
+                        .ok()
+                        .and_then(|response| response.choices.into_iter().next())
+                        .map(|choice| choice.message)
+                        .ok_or_else(|| "No choices in the response".to_string())
+                }
+                (Some(response), None) => Err(response
+                    .body
+                    .get("error")
+                    .and_then(|error| error.get("message"))
+                    .and_then(|message| message.as_str())
+                    .map(String::from)
+                    .unwrap_or_else(|| {
+                        format!("OpenAI responded with status {}", response.status_code)
+                    })),
+                (None, None) => Err("No response".to_string()),
+            };
+            Ok(BatchResult {
+                custom_id: line.custom_id,
+                message: outcome.as_ref().ok().cloned(),
+                error: outcome.err(),
+            })
+        })
+        .collect()
+}
+
+// the client's id for each request, `request-{index}` by default
+pub(crate) fn custom_id_from_body(
+    body: &serde_json::Value,
+    index: usize,
+) -> Result<String, HttpError> {
+    match body.get("custom_id") {
+        None | Some(serde_json::Value::Null) => Ok(format!("request-{index}")),
+        Some(serde_json::Value::String(id))
+            if !id.is_empty() && id.len() <= MAX_CUSTOM_ID_LENGTH =>
+        {
+            Ok(id.clone())
+        }
+        Some(id) => Err(HttpError::bad_request(format!(
+            "Invalid custom_id {id} in request {index}"
+        ))),
+    }
+}
+
+// the batch id of `?id=...`, only made of the characters OpenAI uses since it ends up in a URL
+pub(crate) fn batch_id_from_query(query: Option<&str>) -> Result<String, HttpError> {
+    let id = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("id="))
+        .ok_or_else(|| HttpError::bad_request("Missing 'id' query parameter"))?;
+    match !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        true => Ok(id.to_string()),
+        false => Err(HttpError::bad_request(format!("Invalid batch id '{id}'"))),
+    }
+}
+
+// whether the refused custom_ids still fit in the batch metadata
+pub(crate) fn fits_in_metadata(refused: &[String]) -> bool {
+    serde_json::json!(refused).to_string().len() <= MAX_METADATA_VALUE_LENGTH
+}
+
+// a batch of the uploaded input file's chat completions, remembering the refused requests
+pub(crate) fn create_batch_body(input_file_id: &str, refused: &[String]) -> serde_json::Value {
+    let mut metadata = serde_json::json!({ BATCH_TAG_KEY: BATCH_TAG_VALUE });
+    if !refused.is_empty() {
+        metadata[BATCH_REFUSED_KEY] = serde_json::json!(serde_json::json!(refused).to_string());
+    }
+    serde_json::json!({
+        "input_file_id": input_file_id,
+        "endpoint": BATCH_URL,
+        "completion_window": COMPLETION_WINDOW,
+        "metadata": metadata,
+    })
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct FileObject {
+    pub(crate) id: String,
+}
+
+impl FileObject {
+    pub(crate) fn from_json_slice(response_body: &[u8]) -> Result<Self, anyhow::Error> {
+        serde_json::from_slice(response_body)
+            .map_err(|e| anyhow::anyhow!("Could not parse OpenAI file: {e}"))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct Batch {
+    pub(crate) id: String,
+    pub(crate) status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) completed_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) request_counts: Option<serde_json::Value>,
+    // the files are only read through the results endpoint
+    #[serde(default, skip_serializing)]
+    pub(crate) output_file_id: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub(crate) error_file_id: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub(crate) metadata: Option<std::collections::HashMap<String, String>>,
+}
+
+impl Batch {
+    pub(crate) fn from_json_slice(response_body: &[u8]) -> Result<Self, anyhow::Error> {
+        serde_json::from_slice(response_body)
+            .map_err(|e| anyhow::anyhow!("Could not parse OpenAI batch: {e}"))
+    }
+
+    // whether the batch was created by this component, and not by another client of the API key
+    pub(crate) fn is_tagged(&self) -> bool {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get(BATCH_TAG_KEY))
+            .is_some_and(|value| value == BATCH_TAG_VALUE)
+    }
+
+    // the custom_ids of the requests refused when the batch was created
+    pub(crate) fn refused(&self) -> Vec<String> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get(BATCH_REFUSED_KEY))
+            .and_then(|value| serde_json::from_str(value).ok())
+            .unwrap_or_default()
+    }
+}
+
+// the upstream files and batches endpoints
+pub(crate) struct BatchClient {
+    hostname: Option<String>,
+}
+
+impl BatchClient {
+    pub(crate) fn new(hostname: Option<String>) -> Self {
+        BatchClient { hostname }
+    }
+
+    pub(crate) fn generate_endpoint(&self, path: &str) -> String {
+        generate_endpoint(self.hostname.clone(), path)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn upload_file(
+        &self,
+        jsonl: String,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        let form = Multipart::new()
+            .with_text("purpose", "batch")
+            .with_part(MultipartPart {
+                name: "file".to_string(),
+                filename: Some("batch.jsonl".to_string()),
+                content_type: Some("application/jsonl".to_string()),
+                data: jsonl.into(),
+            });
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(FILES_ENDPOINT),
+            &api_key,
+            forwarded_headers,
+            Some((&form.content_type(), form.into_body()?.into())),
+        )
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn create_batch(
+        &self,
+        input_file_id: &str,
+        refused: &[String],
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        let body = create_batch_body(input_file_id, refused);
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(BATCHES_ENDPOINT),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(&body)?)),
+        )
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn retrieve_batch(
+        &self,
+        batch_id: &str,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        self.get(
+            &format!("{BATCHES_ENDPOINT}/{batch_id}"),
+            api_key,
+            forwarded_headers,
+        )
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn file_content(
+        &self,
+        file_id: &str,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        self.get(
+            &format!("{FILES_ENDPOINT}/{file_id}/content"),
+            api_key,
+            forwarded_headers,
+        )
+    }
+
+    #[cfg(not(test))]
+    fn get(
+        &self,
+        path: &str,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Get,
+            &self.generate_endpoint(path),
+            &api_key,
+            forwarded_headers,
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::openai_payload::Role;
+    use serde_json::json;
+
+    // Mock upstream calls to avoid real HTTP calls: `batch_done` is completed, `batch_missing` unknown,
+    // `batch_foreign` created without the component's tag, `batch_refused` completed with a refused request
+    pub struct MockBatchResponse {
+        status_code: u16,
+        headers: http::HeaderMap,
+        body: String,
+    }
+    impl MockBatchResponse {
+        fn ok(body: String) -> anyhow::Result<Self> {
+            Ok(MockBatchResponse {
+                status_code: 200,
+                headers: http::HeaderMap::new(),
+                body,
+            })
+        }
+        pub fn status_code(&self) -> u16 {
+            self.status_code
+        }
+        pub fn headers(&self) -> &http::HeaderMap {
+            &self.headers
+        }
+        pub fn body(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(self.body.clone().into())
+        }
+    }
+
+    impl BatchClient {
+        pub fn upload_file(
+            &self,
+            jsonl: String,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockBatchResponse> {
+            let lines = jsonl.lines().count();
+            MockBatchResponse::ok(json!({ "id": format!("file-{lines}") }).to_string())
+        }
+
+        pub fn create_batch(
+            &self,
+            input_file_id: &str,
+            refused: &[String],
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockBatchResponse> {
+            MockBatchResponse::ok(
+                json!({
+                    "id": "batch_abc",
+                    "status": "validating",
+                    "input_file_id": input_file_id,
+                    "created_at": 1700000000,
+                    "metadata": create_batch_body(input_file_id, refused)["metadata"]
+                })
+                .to_string(),
+            )
+        }
+
+        pub fn retrieve_batch(
+            &self,
+            batch_id: &str,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockBatchResponse> {
+            let batch = match batch_id {
+                "batch_missing" => {
+                    return Ok(MockBatchResponse {
+                        status_code: 404,
+                        headers: http::HeaderMap::new(),
+                        body: json!({"error": {"message": "No batch found"}}).to_string(),
+                    })
+                }
+                "batch_done" => json!({
+                    "id": batch_id,
+                    "status": "completed",
+                    "request_counts": {"total": 3, "completed": 2, "failed": 1},
+                    "output_file_id": "file-out",
+                    "error_file_id": "file-err",
+                    "metadata": {BATCH_TAG_KEY: BATCH_TAG_VALUE}
+                }),
+                "batch_refused" => json!({
+                    "id": batch_id,
+                    "status": "completed",
+                    "output_file_id": "file-out",
+                    "metadata": create_batch_body("file-in", &["row-3".to_string()])["metadata"]
+                }),
+                "batch_foreign" => json!({
+                    "id": batch_id,
+                    "status": "completed",
+                    "output_file_id": "file-out"
+                }),
+                _ => json!({
+                    "id": batch_id,
+                    "status": "in_progress",
+                    "request_counts": {"total": 3, "completed": 1, "failed": 0},
+                    "metadata": {BATCH_TAG_KEY: BATCH_TAG_VALUE}
+                }),
+            };
+            MockBatchResponse::ok(batch.to_string())
+        }
+
+        pub fn file_content(
+            &self,
+            file_id: &str,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockBatchResponse> {
+            MockBatchResponse::ok(match file_id {
+                "file-err" => output_line("request-2", 400, json!({"error": {"message": "Bad"}})),
+                _ => [
+                    output_line("request-0", 200, chat_completion("Paris")),
+                    output_line("request-1", 200, chat_completion("Rome")),
+                ]
+                .join(""),
+            })
+        }
+    }
+
+    fn chat_completion(content: &str) -> serde_json::Value {
+        json!({"choices": [{"message": {"role": "assistant", "content": content}, "finish_reason": "stop"}]})
+    }
+
+    fn output_line(custom_id: &str, status_code: u16, body: serde_json::Value) -> String {
+        let line = json!({
+            "id": "batch_req_1",
+            "custom_id": custom_id,
+            "response": {"status_code": status_code, "request_id": "req_1", "body": body},
+            "error": null
+        });
+        format!("{line}\n")
+    }
+
+    #[test]
+    fn test_encode_jsonl() {
+        let payload = |content: &str| {
+            OpenAIPayload::new(
+                "gpt-4o".to_string(),
+                vec![Message {
+                    role: Role::User,
+                    content: content.to_string(),
+                }],
+                Some(10),
+            )
+        };
+        let jsonl = encode_jsonl(&[
+            ("a".to_string(), payload("Hi")),
+            ("b".to_string(), payload("Bye")),
+        ])
+        .unwrap();
+        let lines: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(jsonl.ends_with('\n'));
+        assert_eq!(
+            lines,
+            vec![
+                json!({
+                    "custom_id": "a",
+                    "method": "POST",
+                    "url": "/v1/chat/completions",
+                    "body": {"model": "gpt-4o", "messages": [{"role": "user", "content": "Hi"}], "max_completion_tokens": 10}
+                }),
+                json!({
+                    "custom_id": "b",
+                    "method": "POST",
+                    "url": "/v1/chat/completions",
+                    "body": {"model": "gpt-4o", "messages": [{"role": "user", "content": "Bye"}], "max_completion_tokens": 10}
+                }),
+            ]
+        );
+        assert_eq!(encode_jsonl(&[]).unwrap(), "");
+    }
+
+    #[test]
+    fn test_decode_jsonl() {
+        let jsonl = [
+            output_line("a", 200, chat_completion("Paris")),
+            String::from("\n"),
+            output_line("b", 429, json!({"error": {"message": "Rate limited"}})),
+            output_line("c", 500, json!({})),
+            json!({"custom_id": "d", "response": null, "error": {"code": "x", "message": "Expired"}})
+                .to_string(),
+        ]
+        .join("");
+        let results = decode_jsonl(&jsonl).unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].custom_id, "a");
+        let message = results[0].message.as_ref().unwrap();
+        assert_eq!(message.role, Role::Assistant);
+        assert_eq!(message.content, "Paris");
+        assert_eq!(results[1].error.as_deref(), Some("Rate limited"));
+        assert_eq!(
+            results[2].error.as_deref(),
+            Some("OpenAI responded with status 500")
+        );
+        assert_eq!(results[3].error.as_deref(), Some("Expired"));
+        assert!(results[3].message.is_none());
+
+        assert_eq!(
+            serde_json::to_value(&results[0]).unwrap(),
+            json!({"custom_id": "a", "role": "assistant", "content": "Paris"})
+        );
+        assert_eq!(
+            serde_json::to_value(&results[1]).unwrap(),
+            json!({"custom_id": "b", "error": "Rate limited"})
+        );
+        assert!(decode_jsonl("not json").is_err());
+    }
+
+    #[test]
+    fn test_custom_id_from_body() {
+        assert_eq!(custom_id_from_body(&json!({}), 3).unwrap(), "request-3");
+        assert_eq!(
+            custom_id_from_body(&json!({"custom_id": "row-42"}), 0).unwrap(),
+            "row-42"
+        );
+        for id in [
+            json!(""),
+            json!(1),
+            json!("a".repeat(MAX_CUSTOM_ID_LENGTH + 1)),
+        ] {
+            assert!(custom_id_from_body(&json!({ "custom_id": id }), 0).is_err());
+        }
+    }
+
+    #[test]
+    fn test_batch_id_from_query() {
+        assert_eq!(
+            batch_id_from_query(Some("id=batch_abc-1")).unwrap(),
+            "batch_abc-1"
+        );
+        assert_eq!(
+            batch_id_from_query(Some("x=1&id=batch_abc")).unwrap(),
+            "batch_abc"
+        );
+        assert_eq!(
+            batch_id_from_query(None).unwrap_err().to_string(),
+            "Missing 'id' query parameter"
+        );
+        for query in ["id=", "id=../files", "id=batch%2F1"] {
+            assert!(batch_id_from_query(Some(query)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_batch_serialization() {
+        let batch = Batch::from_json_slice(
+            json!({"id": "batch_1", "status": "completed", "output_file_id": "file-out"})
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(batch.output_file_id.as_deref(), Some("file-out"));
+        assert!(!batch.is_tagged());
+        assert_eq!(
+            serde_json::to_value(&batch).unwrap(),
+            json!({"id": "batch_1", "status": "completed"})
+        );
+
+        let batch = Batch::from_json_slice(
+            json!({"id": "batch_1", "status": "completed", "metadata": {"created_by": "edgee-openai-component"}})
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+        assert!(batch.is_tagged());
+        assert_eq!(
+            serde_json::to_value(&batch).unwrap(),
+            json!({"id": "batch_1", "status": "completed"})
+        );
+    }
+
+    #[test]
+    fn test_batch_generate_endpoint() {
+        assert_eq!(
+            BatchClient::new(None).generate_endpoint(FILES_ENDPOINT),
+            "https://api.openai.com/v1/files"
+        );
+        assert_eq!(
+            BatchClient::new(Some("custom.example.com".to_string()))
+                .generate_endpoint(BATCHES_ENDPOINT),
+            "https://custom.example.com/v1/batches"
+        );
+        assert_eq!(
+            create_batch_body("file-1", &[]),
+            json!({"input_file_id": "file-1", "endpoint": "/v1/chat/completions", "completion_window": "24h", "metadata": {"created_by": "edgee-openai-component"}})
+        );
+        assert_eq!(
+            create_batch_body("file-1", &["row-1".to_string()])["metadata"],
+            json!({"created_by": "edgee-openai-component", "refused": r#"["row-1"]"#})
+        );
+    }
+
+    #[test]
+    fn test_batch_refused() {
+        let refused = vec!["row-1".to_string(), "row-2".to_string()];
+        let batch = Batch::from_json_slice(
+            json!({"id": "batch_1", "status": "completed", "metadata": create_batch_body("file-1", &refused)["metadata"]})
+                .to_string()
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(batch.refused(), refused);
+
+        assert!(fits_in_metadata(&refused));
+        assert!(!fits_in_metadata(&vec!["x".repeat(64); 8]));
+    }
+}
@@ -0,0 +1,320 @@
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::HttpError;
+use crate::openai_payload::{
+    generate_endpoint, Message, OpenAIChoice, OpenAIResponse, Role, Usage,
+};
+
+const ENDPOINT: &str = "/v1/completions";
+// the model would otherwise go on writing the user's next turn
+const STOP_SEQUENCE: &str = "\nUser:";
+
+/*
+ * Options of the legacy completions endpoint that have no chat equivalent,
+ * read from the client's request body.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct CompletionOptions {
+    pub(crate) suffix: Option<String>,
+    pub(crate) echo: bool,
+    pub(crate) best_of: Option<u32>,
+}
+
+impl CompletionOptions {
+    pub(crate) fn from_request_body(body: &serde_json::Value) -> Result<Self, HttpError> {
+        let suffix = match body.get("suffix") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::String(suffix)) => Some(suffix.clone()),
+            Some(suffix) => return Err(HttpError::bad_request(format!("Invalid suffix {suffix}"))),
+        };
+        let echo = match body.get("echo") {
+            None | Some(serde_json::Value::Null) => false,
+            Some(serde_json::Value::Bool(echo)) => *echo,
+            Some(echo) => return Err(HttpError::bad_request(format!("Invalid echo {echo}"))),
+        };
+        let best_of = match body.get("best_of") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(best_of) => Some(
+                best_of
+                    .as_u64()
+                    .filter(|b| *b > 0)
+                    .and_then(|b| u32::try_from(b).ok())
+                    .ok_or_else(|| HttpError::bad_request(format!("Invalid best_of {best_of}")))?,
+            ),
+        };
+        Ok(CompletionOptions {
+            suffix,
+            echo,
+            best_of,
+        })
+    }
+
+    // how many completions are generated upstream, each of them billed
+    pub(crate) fn completions_count(&self) -> u32 {
+        self.best_of.unwrap_or(1)
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct CompletionsPayload {
+    model: String,
+    prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    echo: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    best_of: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    stop: Vec<String>,
+    // the part of the prompt made of system messages, never echoed back
+    #[serde(skip)]
+    instructions: String,
+}
+
+impl CompletionsPayload {
+    pub(crate) fn new(
+        model: String,
+        messages: &[Message],
+        max_tokens: Option<u32>,
+        options: CompletionOptions,
+    ) -> Self {
+        let (instructions, dialogue) = prompt_from_messages(messages);
+        CompletionsPayload {
+            model,
+            prompt: format!("{instructions}{dialogue}"),
+            max_tokens,
+            suffix: options.suffix,
+            echo: options.echo,
+            best_of: options.best_of,
+            temperature: None,
+            stop: vec![STOP_SEQUENCE.to_string()],
+            instructions,
+        }
+    }
+
+    pub(crate) fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        hostname: Option<String>,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(self)?)),
+        )
+    }
+
+    /*
+     * Maps the `text` choices to assistant messages, so the rest of the pipeline
+     * handles them like chat replies.
+     */
+    pub(crate) fn parse_response(
+        &self,
+        response_body: &str,
+    ) -> Result<OpenAIResponse, anyhow::Error> {
+        let response: CompletionsResponse = serde_json::from_str(response_body)?;
+        let choices = response
+            .choices
+            .into_iter()
+            .map(|choice| {
+                let text = match self.echo {
+                    true => choice
+                        .text
+                        .strip_prefix(&self.instructions)
+                        .map(String::from)
+                        .unwrap_or(choice.text),
+                    false => choice.text,
+                };
+                OpenAIChoice {
+                    message: Message {
+                        role: Role::Assistant,
+                        content: text,
+                    },
+                    finish_reason: choice.finish_reason,
+                }
+            })
+            .collect();
+        Ok(OpenAIResponse {
+            id: response.id,
+            choices,
+            usage: response.usage,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct CompletionsChoice {
+    text: String,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CompletionsResponse {
+    #[serde(default)]
+    id: Option<String>,
+    choices: Vec<CompletionsChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+/*
+ * Renders the conversation as a transcript ending with the assistant's turn.
+ * Returns the system messages and the dialogue separately.
+ */
+fn prompt_from_messages(messages: &[Message]) -> (String, String) {
+    let mut instructions = String::new();
+    let mut dialogue = String::new();
+    for message in messages {
+        match message.role {
+            Role::System | Role::Developer => {
+                instructions.push_str(&message.content);
+                instructions.push_str("\n\n");
+            }
+            Role::User => dialogue.push_str(&format!("User: {}\n", message.content)),
+            Role::Assistant => dialogue.push_str(&format!("Assistant: {}\n", message.content)),
+            Role::Tool => dialogue.push_str(&format!("Tool: {}\n", message.content)),
+        }
+    }
+    dialogue.push_str("Assistant:");
+    (instructions, dialogue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    fn transcript_payload(options: CompletionOptions) -> CompletionsPayload {
+        CompletionsPayload::new(
+            "gpt-3.5-turbo-instruct".to_string(),
+            &[
+                message(Role::System, "Be brief."),
+                message(Role::User, "Hi"),
+                message(Role::Assistant, "Hello!"),
+                message(Role::User, "Name a color"),
+            ],
+            Some(16),
+            options,
+        )
+    }
+
+    #[test]
+    fn test_completions_payload_serialization() {
+        let payload = transcript_payload(CompletionOptions::default());
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "model": "gpt-3.5-turbo-instruct",
+                "prompt": "Be brief.\n\nUser: Hi\nAssistant: Hello!\nUser: Name a color\nAssistant:",
+                "max_tokens": 16,
+                "stop": ["\nUser:"]
+            })
+        );
+
+        let payload = transcript_payload(CompletionOptions {
+            suffix: Some("!".to_string()),
+            echo: true,
+            best_of: Some(3),
+        });
+        let value = serde_json::to_value(&payload).unwrap();
+        assert_eq!(value["suffix"], "!");
+        assert_eq!(value["echo"], true);
+        assert_eq!(value["best_of"], 3);
+
+        let payload = transcript_payload(CompletionOptions::default()).with_temperature(Some(0.5));
+        assert_eq!(serde_json::to_value(&payload).unwrap()["temperature"], 0.5);
+    }
+
+    #[test]
+    fn test_completion_options_from_request_body() {
+        let options = CompletionOptions::from_request_body(&json!({
+            "suffix": "end", "echo": true, "best_of": 2
+        }))
+        .unwrap();
+        assert_eq!(options.suffix.as_deref(), Some("end"));
+        assert!(options.echo);
+        assert_eq!(options.completions_count(), 2);
+        assert_eq!(
+            CompletionOptions::from_request_body(&json!({})).unwrap(),
+            CompletionOptions::default()
+        );
+    }
+
+    #[test]
+    fn test_completion_options_invalid() {
+        for body in [
+            json!({"suffix": 1}),
+            json!({"echo": "yes"}),
+            json!({"best_of": 0}),
+        ] {
+            let err = CompletionOptions::from_request_body(&body).unwrap_err();
+            assert_eq!(err.status_code, http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_completions_parse_response() {
+        let body = r#"{
+            "choices": [{"text": " Blue.", "index": 0, "finish_reason": "stop"}],
+            "usage": {"prompt_tokens": 20, "completion_tokens": 2, "total_tokens": 22}
+        }"#;
+        let response = transcript_payload(CompletionOptions::default())
+            .parse_response(body)
+            .unwrap();
+        assert_eq!(response.choices[0].message.role, Role::Assistant);
+        assert_eq!(response.choices[0].message.content, " Blue.");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(response.usage.unwrap().completion_tokens, 2);
+    }
+
+    #[test]
+    fn test_completions_parse_response_echo_hides_instructions() {
+        let payload = transcript_payload(CompletionOptions {
+            echo: true,
+            ..Default::default()
+        });
+        let body = json!({"choices": [{"text": format!("{} Blue.", payload.prompt)}]});
+        let response = payload.parse_response(&body.to_string()).unwrap();
+        assert_eq!(
+            response.choices[0].message.content,
+            "User: Hi\nAssistant: Hello!\nUser: Name a color\nAssistant: Blue."
+        );
+    }
+
+    #[test]
+    fn test_completions_generate_endpoint() {
+        assert_eq!(
+            transcript_payload(CompletionOptions::default()).generate_endpoint(None),
+            "https://api.openai.com/v1/completions"
+        );
+    }
+}
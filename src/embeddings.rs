@@ -0,0 +1,256 @@
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::HttpError;
+use crate::openai_payload::generate_endpoint;
+use crate::redaction::Redactor;
+
+pub(crate) const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+const ENDPOINT: &str = "/v1/embeddings";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub(crate) enum EmbeddingsInput {
+    Text(String),
+    Texts(Vec<String>),
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct EmbeddingsPayload {
+    model: String,
+    input: EmbeddingsInput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dimensions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding_format: Option<String>,
+}
+
+impl EmbeddingsPayload {
+    // validates the client's request body, the model being resolved by the caller
+    pub(crate) fn from_request_body(
+        model: String,
+        body: &serde_json::Value,
+    ) -> Result<Self, HttpError> {
+        let input = match body.get("input") {
+            Some(input) => serde_json::from_value::<EmbeddingsInput>(input.clone())
+                .ok()
+                .filter(|input| match input {
+                    EmbeddingsInput::Text(text) => !text.is_empty(),
+                    EmbeddingsInput::Texts(texts) => {
+                        !texts.is_empty() && texts.iter().all(|text| !text.is_empty())
+                    }
+                })
+                .ok_or_else(|| {
+                    HttpError::bad_request(format!(
+                        "Invalid input {input}, expected a string or an array of strings"
+                    ))
+                })?,
+            None => {
+                return Err(HttpError::bad_request(
+                    "Missing 'input' field in request body",
+                ))
+            }
+        };
+        let dimensions = match body.get("dimensions") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(dimensions) => Some(
+                dimensions
+                    .as_u64()
+                    .filter(|d| *d > 0)
+                    .and_then(|d| u32::try_from(d).ok())
+                    .ok_or_else(|| {
+                        HttpError::bad_request(format!("Invalid dimensions {dimensions}"))
+                    })?,
+            ),
+        };
+        let encoding_format = match body.get("encoding_format") {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::String(format)) if format == "float" || format == "base64" => {
+                Some(format.clone())
+            }
+            Some(format) => {
+                return Err(HttpError::bad_request(format!(
+                    "Invalid encoding_format {format}"
+                )))
+            }
+        };
+        Ok(EmbeddingsPayload {
+            model,
+            input,
+            dimensions,
+            encoding_format,
+        })
+    }
+
+    pub(crate) fn texts(&self) -> Vec<String> {
+        match &self.input {
+            EmbeddingsInput::Text(text) => vec![text.clone()],
+            EmbeddingsInput::Texts(texts) => texts.clone(),
+        }
+    }
+
+    pub(crate) fn redact(&mut self, redactor: &mut Redactor) {
+        self.input = match &self.input {
+            EmbeddingsInput::Text(text) => EmbeddingsInput::Text(redactor.redact(text)),
+            EmbeddingsInput::Texts(texts) => {
+                EmbeddingsInput::Texts(texts.iter().map(|text| redactor.redact(text)).collect())
+            }
+        };
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        hostname: Option<String>,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(self)?)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Mock send method to avoid real HTTP call, returning one vector per input,
+    // and rejecting the "upstream error" input
+    pub struct MockEmbeddingsResponse {
+        status: u16,
+        headers: http::HeaderMap,
+        body: serde_json::Value,
+    }
+    impl MockEmbeddingsResponse {
+        pub fn status_code(&self) -> u16 {
+            self.status
+        }
+        pub fn headers(&self) -> &http::HeaderMap {
+            &self.headers
+        }
+        pub fn body(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(self.body.to_string().into())
+        }
+    }
+
+    impl EmbeddingsPayload {
+        pub fn send(
+            &self,
+            _hostname: Option<String>,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockEmbeddingsResponse> {
+            let count = match &self.input {
+                EmbeddingsInput::Text(_) => 1,
+                EmbeddingsInput::Texts(texts) => texts.len(),
+            };
+            let dimensions = self.dimensions.unwrap_or(3) as usize;
+            if self.input == EmbeddingsInput::Text("upstream error".to_string()) {
+                return Ok(MockEmbeddingsResponse {
+                    status: 400,
+                    headers: http::HeaderMap::new(),
+                    body: serde_json::json!({"error": {"message": "Rejected upstream", "type": "invalid_request_error"}}),
+                });
+            }
+            Ok(MockEmbeddingsResponse {
+                status: 200,
+                headers: http::HeaderMap::new(),
+                body: json!({
+                    "object": "list",
+                    "model": self.model,
+                    "data": (0..count)
+                        .map(|index| json!({
+                            "object": "embedding",
+                            "index": index,
+                            "embedding": vec![0.5; dimensions],
+                        }))
+                        .collect::<Vec<_>>(),
+                    "usage": { "prompt_tokens": 1000, "total_tokens": 1000 }
+                }),
+            })
+        }
+    }
+
+    fn parse_payload(body: serde_json::Value) -> Result<EmbeddingsPayload, HttpError> {
+        EmbeddingsPayload::from_request_body(DEFAULT_EMBEDDING_MODEL.to_string(), &body)
+    }
+
+    #[test]
+    fn test_embeddings_payload_serialization() {
+        let payload = parse_payload(json!({"input": "Hello"})).unwrap();
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({"model": "text-embedding-3-small", "input": "Hello"})
+        );
+
+        let payload = parse_payload(json!({
+            "input": ["Hello", "World"],
+            "dimensions": 256,
+            "encoding_format": "base64"
+        }))
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "model": "text-embedding-3-small",
+                "input": ["Hello", "World"],
+                "dimensions": 256,
+                "encoding_format": "base64"
+            })
+        );
+    }
+
+    #[test]
+    fn test_embeddings_payload_redact() {
+        let mut payload =
+            parse_payload(json!({"input": ["Mail jane@example.com", "Hello"]})).unwrap();
+        payload.redact(&mut Redactor::new(&[crate::redaction::PiiKind::Email]));
+        assert_eq!(payload.texts(), vec!["Mail [EMAIL_1]", "Hello"]);
+    }
+
+    #[test]
+    fn test_embeddings_payload_invalid_input() {
+        assert_eq!(
+            parse_payload(json!({})).unwrap_err().to_string(),
+            "Missing 'input' field in request body"
+        );
+        for input in [json!(""), json!([]), json!([1, 2]), json!(["ok", ""])] {
+            let err = parse_payload(json!({ "input": input })).unwrap_err();
+            assert_eq!(err.status_code, http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_embeddings_payload_invalid_options() {
+        let err = parse_payload(json!({"input": "Hi", "dimensions": 0})).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid dimensions 0");
+        let err = parse_payload(json!({"input": "Hi", "encoding_format": "int8"})).unwrap_err();
+        assert_eq!(err.to_string(), r#"Invalid encoding_format "int8""#);
+    }
+
+    #[test]
+    fn test_embeddings_generate_endpoint() {
+        let payload = parse_payload(json!({"input": "Hello"})).unwrap();
+        assert_eq!(
+            payload.generate_endpoint(None),
+            "https://api.openai.com/v1/embeddings"
+        );
+        assert_eq!(
+            payload.generate_endpoint(Some("custom.example.com".to_string())),
+            "https://custom.example.com/v1/embeddings"
+        );
+    }
+}
@@ -8,6 +8,11 @@ pub trait FromBody: Sized {
     fn from_body(body: IncomingBody) -> Result<Self> {
         Self::from_data(body.read()?)
     }
+
+    // like `from_body`, failing once the body is over `limit` bytes
+    fn from_body_limited(body: IncomingBody, limit: usize) -> Result<Self> {
+        Self::from_data(body.read_limited(Some(limit))?)
+    }
 }
 
 pub trait IntoBody: Sized {
@@ -25,6 +30,11 @@ impl FromBody for IncomingBody {
     fn from_body(body: IncomingBody) -> Result<Self> {
         Ok(body)
     }
+
+    // the stream is handed over unread, it's up to its reader to limit it
+    fn from_body_limited(body: IncomingBody, _: usize) -> Result<Self> {
+        Ok(body)
+    }
 }
 
 impl FromBody for Bytes {
@@ -47,6 +57,10 @@ impl FromBody for () {
     fn from_body(_: IncomingBody) -> Result<Self> {
         Ok(())
     }
+
+    fn from_body_limited(_: IncomingBody, _: usize) -> Result<Self> {
+        Ok(())
+    }
 }
 
 impl IntoBody for () {
@@ -150,6 +164,245 @@ impl<T: Into<Bytes>> IntoBody for Html<T> {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct EventStream<T>(pub T);
+
+impl<T: Into<Bytes>> IntoBody for EventStream<T> {
+    fn into_body(self) -> Result<Bytes> {
+        Ok(self.0.into())
+    }
+
+    fn extend_response_parts(&self, parts: &mut http::response::Parts) {
+        parts
+            .headers
+            .entry(http::header::CONTENT_TYPE)
+            .or_insert(http::HeaderValue::from_static("text/event-stream"));
+        parts
+            .headers
+            .entry(http::header::CACHE_CONTROL)
+            .or_insert(http::HeaderValue::from_static("no-cache"));
+    }
+}
+
+/*
+ * Binary data like audio or images, sent with its content type and length.
+ */
+#[derive(Debug, Clone)]
+pub struct Binary {
+    pub content_type: http::HeaderValue,
+    pub data: Bytes,
+}
+
+impl IntoBody for Binary {
+    fn into_body(self) -> Result<Bytes> {
+        Ok(self.data)
+    }
+
+    fn extend_response_parts(&self, parts: &mut http::response::Parts) {
+        parts
+            .headers
+            .insert(http::header::CONTENT_TYPE, self.content_type.clone());
+        parts
+            .headers
+            .insert(http::header::CONTENT_LENGTH, self.data.len().into());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MultipartPart {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MultipartLimits {
+    // the whole body, parts and delimiters included
+    pub max_size: usize,
+    pub max_parts: usize,
+}
+
+/*
+ * A multipart/form-data body.
+ * The boundary is part of the content type, so parsing takes it along with the data.
+ */
+#[derive(Debug, Clone)]
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<MultipartPart>,
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Multipart {
+    pub fn new() -> Self {
+        Self {
+            boundary: format!("----edgee-{}", uuid::Uuid::new_v4().simple()),
+            parts: Vec::new(),
+        }
+    }
+
+    pub fn with_text(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.parts.push(MultipartPart {
+            name: name.to_string(),
+            filename: None,
+            content_type: None,
+            data: Bytes::from(value.into()),
+        });
+        self
+    }
+
+    pub fn with_part(mut self, part: MultipartPart) -> Self {
+        self.parts.push(part);
+        self
+    }
+
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    pub fn part(&self, name: &str) -> Option<&MultipartPart> {
+        self.parts.iter().find(|part| part.name == name)
+    }
+
+    pub fn text(&self, name: &str) -> Option<&str> {
+        self.part(name)
+            .and_then(|part| std::str::from_utf8(&part.data).ok())
+    }
+
+    pub fn parse(content_type: &str, data: Bytes, limits: &MultipartLimits) -> Result<Self> {
+        if data.len() > limits.max_size {
+            return Err(super::HttpError::new(
+                http::StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Request body exceeds {} bytes", limits.max_size),
+            )
+            .into());
+        }
+        let boundary = multipart_boundary(content_type)
+            .ok_or_else(|| anyhow::anyhow!("Expected a multipart/form-data content type"))?;
+
+        let delimiter = format!("--{boundary}");
+        let next_delimiter = format!("\r\n--{boundary}");
+        let mut position = find_bytes(&data, delimiter.as_bytes(), 0)
+            .ok_or_else(|| anyhow::anyhow!("Missing multipart boundary"))?
+            + delimiter.len();
+        let mut parts = Vec::new();
+        loop {
+            let rest = &data[position..];
+            if rest.starts_with(b"--") {
+                break;
+            }
+            if !rest.starts_with(b"\r\n") {
+                return Err(anyhow::anyhow!("Invalid multipart boundary line"));
+            }
+            if parts.len() == limits.max_parts {
+                return Err(anyhow::anyhow!(
+                    "Multipart body has more than {} parts",
+                    limits.max_parts
+                ));
+            }
+            let start = position + 2;
+            let end = find_bytes(&data, next_delimiter.as_bytes(), start)
+                .ok_or_else(|| anyhow::anyhow!("Unterminated multipart part"))?;
+            parts.push(parse_part(data.slice(start..end))?);
+            position = end + next_delimiter.len();
+        }
+        Ok(Self { boundary, parts })
+    }
+}
+
+impl IntoBody for Multipart {
+    fn into_body(self) -> Result<Bytes> {
+        use bytes::{BufMut, BytesMut};
+
+        let mut buf = BytesMut::new();
+        for part in &self.parts {
+            buf.put(format!("--{}\r\n", self.boundary).as_bytes());
+            let mut disposition = format!("form-data; name=\"{}\"", part.name);
+            if let Some(filename) = &part.filename {
+                disposition.push_str(&format!("; filename=\"{filename}\""));
+            }
+            buf.put(format!("Content-Disposition: {disposition}\r\n").as_bytes());
+            if let Some(content_type) = &part.content_type {
+                buf.put(format!("Content-Type: {content_type}\r\n").as_bytes());
+            }
+            buf.put(&b"\r\n"[..]);
+            buf.put(part.data.clone());
+            buf.put(&b"\r\n"[..]);
+        }
+        buf.put(format!("--{}--\r\n", self.boundary).as_bytes());
+        Ok(buf.freeze())
+    }
+
+    fn extend_response_parts(&self, parts: &mut http::response::Parts) {
+        if let Ok(content_type) = http::HeaderValue::from_str(&self.content_type()) {
+            parts
+                .headers
+                .entry(http::header::CONTENT_TYPE)
+                .or_insert(content_type);
+        }
+    }
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|index| index + from)
+}
+
+fn parse_part(data: Bytes) -> Result<MultipartPart> {
+    let headers_end = find_bytes(&data, b"\r\n\r\n", 0)
+        .ok_or_else(|| anyhow::anyhow!("Missing multipart part headers"))?;
+    let headers = std::str::from_utf8(&data[..headers_end])?;
+
+    let (mut name, mut filename, mut content_type) = (None, None, None);
+    for line in headers.split("\r\n") {
+        let Some((header, value)) = line.split_once(':') else {
+            continue;
+        };
+        match header.trim().to_ascii_lowercase().as_str() {
+            "content-disposition" => {
+                for param in value.split(';').skip(1) {
+                    match param.trim().split_once('=') {
+                        Some(("name", value)) => name = Some(value.trim_matches('"').to_string()),
+                        Some(("filename", value)) => {
+                            filename = Some(value.trim_matches('"').to_string())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            "content-type" => content_type = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+    Ok(MultipartPart {
+        name: name.ok_or_else(|| anyhow::anyhow!("Multipart part without a name"))?,
+        filename,
+        content_type,
+        data: data.slice(headers_end + 4..),
+    })
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -285,6 +538,21 @@ mod tests {
         assert_eq!(content_type, "application/json");
     }
 
+    #[test]
+    fn test_event_stream_extend_response_parts_sets_content_type() {
+        let stream = EventStream("data: [DONE]\n\n");
+        let (mut parts, _) = http::response::Response::new("ok").into_parts();
+        stream.extend_response_parts(&mut parts);
+        assert_eq!(
+            parts.headers.get(http::header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+        assert_eq!(
+            parts.headers.get(http::header::CACHE_CONTROL).unwrap(),
+            "no-cache"
+        );
+    }
+
     #[test]
     fn test_option_extend_response_parts_some() {
         #[derive(serde::Serialize)]
@@ -384,4 +652,102 @@ mod tests {
         // Should remain as "application/json" since or_insert does not overwrite
         assert_eq!(content_type, "text/html; charset=utf-8");
     }
+
+    const LIMITS: MultipartLimits = MultipartLimits {
+        max_size: 1024,
+        max_parts: 4,
+    };
+
+    #[test]
+    fn test_multipart_roundtrip() {
+        let form = Multipart::new()
+            .with_text("model", "whisper-1")
+            .with_part(MultipartPart {
+                name: "file".to_string(),
+                filename: Some("audio.mp3".to_string()),
+                content_type: Some("audio/mpeg".to_string()),
+                data: Bytes::from_static(b"\xff\xfb\r\n--binary"),
+            });
+        let content_type = form.content_type();
+        let data = form.into_body().unwrap();
+
+        let parsed = Multipart::parse(&content_type, data, &LIMITS).unwrap();
+        assert_eq!(parsed.text("model"), Some("whisper-1"));
+        let file = parsed.part("file").unwrap();
+        assert_eq!(file.filename.as_deref(), Some("audio.mp3"));
+        assert_eq!(file.content_type.as_deref(), Some("audio/mpeg"));
+        assert_eq!(file.data, Bytes::from_static(b"\xff\xfb\r\n--binary"));
+        assert!(parsed.part("missing").is_none());
+    }
+
+    #[test]
+    fn test_multipart_parse() {
+        let data = "preamble\r\n--xyz\r\nContent-Disposition: form-data; name=\"language\"\r\n\r\nfr\r\n--xyz--\r\n";
+        let parsed = Multipart::parse(
+            r#"multipart/form-data; boundary="xyz""#,
+            Bytes::from(data),
+            &LIMITS,
+        )
+        .unwrap();
+        assert_eq!(parsed.text("language"), Some("fr"));
+    }
+
+    #[test]
+    fn test_multipart_parse_invalid() {
+        let data = Bytes::from("--xyz\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1");
+        assert!(
+            Multipart::parse("multipart/form-data; boundary=xyz", data.clone(), &LIMITS).is_err()
+        );
+        assert!(Multipart::parse("application/json", data, &LIMITS).is_err());
+    }
+
+    #[test]
+    fn test_multipart_parse_limits() {
+        let form = (0..5).fold(Multipart::new(), |form, i| {
+            form.with_text(&format!("f{i}"), "x")
+        });
+        let content_type = form.content_type();
+        let data = form.into_body().unwrap();
+        let err = Multipart::parse(&content_type, data.clone(), &LIMITS).unwrap_err();
+        assert_eq!(err.to_string(), "Multipart body has more than 4 parts");
+
+        let limits = MultipartLimits {
+            max_size: 10,
+            max_parts: 10,
+        };
+        let err = Multipart::parse(&content_type, data, &limits).unwrap_err();
+        let http_err = err.downcast_ref::<super::super::HttpError>().unwrap();
+        assert_eq!(http_err.status_code, http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_multipart_extend_response_parts_sets_content_type() {
+        let form = Multipart::new();
+        let (mut parts, _) = http::response::Response::new("ok").into_parts();
+        form.extend_response_parts(&mut parts);
+        assert_eq!(
+            parts.headers.get(http::header::CONTENT_TYPE).unwrap(),
+            form.content_type().as_str()
+        );
+    }
+
+    #[test]
+    fn test_binary_into_body_and_extend_response_parts() {
+        let binary = Binary {
+            content_type: http::HeaderValue::from_static("audio/mpeg"),
+            data: Bytes::from_static(b"\xff\xfb\x90"),
+        };
+        let (mut parts, _) = http::response::Response::new("ok").into_parts();
+        parts.headers.insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/json"),
+        );
+        binary.extend_response_parts(&mut parts);
+        assert_eq!(parts.headers[http::header::CONTENT_TYPE], "audio/mpeg");
+        assert_eq!(parts.headers[http::header::CONTENT_LENGTH], "3");
+        assert_eq!(
+            binary.into_body().unwrap(),
+            Bytes::from_static(b"\xff\xfb\x90")
+        );
+    }
 }
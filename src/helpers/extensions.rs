@@ -3,6 +3,7 @@ use http::header::{HeaderName, HeaderValue};
 use http::uri;
 use serde::de::DeserializeOwned;
 
+use super::HttpError;
 use crate::bindings::wasi::http::types::{
     ErrorCode, Headers, IncomingBody, IncomingRequest, Method, ResponseOutparam, Scheme,
 };
@@ -116,6 +117,11 @@ impl From<http::header::HeaderMap> for Headers {
 
 impl IncomingBody {
     pub fn read(&self) -> anyhow::Result<Bytes> {
+        self.read_limited(None)
+    }
+
+    // reads the body, stopping with a 413 as soon as it's over `limit` bytes
+    pub fn read_limited(&self, limit: Option<usize>) -> anyhow::Result<Bytes> {
         use bytes::BytesMut;
 
         use crate::bindings::wasi::io::streams::StreamError;
@@ -128,9 +134,7 @@ impl IncomingBody {
 
         loop {
             match stream.read(4096) {
-                Ok(frame) => {
-                    bytes.extend_from_slice(&frame);
-                }
+                Ok(frame) => append_frame(&mut bytes, &frame, limit)?,
                 Err(StreamError::Closed) => break,
                 Err(err) => anyhow::bail!("Failed reading request body: {err}"),
             }
@@ -145,6 +149,22 @@ impl IncomingBody {
     }
 }
 
+fn append_frame(
+    bytes: &mut bytes::BytesMut,
+    frame: &[u8],
+    limit: Option<usize>,
+) -> anyhow::Result<()> {
+    if let Some(limit) = limit.filter(|limit| bytes.len() + frame.len() > *limit) {
+        return Err(HttpError::new(
+            http::StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body exceeds {limit} bytes"),
+        )
+        .into());
+    }
+    bytes.extend_from_slice(frame);
+    Ok(())
+}
+
 impl ResponseOutparam {
     pub fn error(self, code: ErrorCode) {
         ResponseOutparam::set(self, Err(code));
@@ -217,6 +237,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_append_frame_limit() {
+        let mut bytes = bytes::BytesMut::new();
+        super::append_frame(&mut bytes, b"abcd", Some(6)).unwrap();
+        super::append_frame(&mut bytes, b"ef", Some(6)).unwrap();
+        let err = super::append_frame(&mut bytes, b"g", Some(6)).unwrap_err();
+        assert_eq!(err.to_string(), "Request body exceeds 6 bytes");
+        assert_eq!(&bytes[..], b"abcdef");
+        super::append_frame(&mut bytes, b"g", None).unwrap();
+        assert_eq!(&bytes[..], b"abcdefg");
+    }
+
     #[test]
     fn test_try_from_method_invalid() {
         // Assuming there's a variant not covered, e.g., an unknown value
@@ -11,8 +11,9 @@ mod extensions;
 
 // Request handling helpers
 
-pub fn run<I, O, F>(req: IncomingRequest, response_out: ResponseOutparam, handler: F)
+pub fn run<I, O, C, F>(req: IncomingRequest, response_out: ResponseOutparam, check: C, handler: F)
 where
+    C: FnOnce(&http::request::Parts) -> Result<Option<usize>>,
     F: FnOnce(Request<I>) -> Result<Response<O>>,
     I: FromBody,
     O: IntoBody,
@@ -20,12 +21,36 @@ where
     let req: Request<_> = req.try_into().unwrap();
 
     let (parts, body) = req.into_parts();
-    let body = match I::from_body(body) {
+    // checks on the headers alone, before reading a body that may be large,
+    // returning how many bytes of body may be read
+    let body_limit = match check(&parts) {
+        Ok(body_limit) => body_limit,
+        Err(err) => {
+            eprintln!("Errored during request checks: {err}");
+
+            let status_code = err
+                .downcast_ref::<HttpError>()
+                .map(|err| err.status_code)
+                .unwrap_or(StatusCode::BAD_REQUEST);
+            let res = json_error_response(status_code, err);
+            response_out.send(res).expect("Failed to send response");
+            return;
+        }
+    };
+    let body = match body_limit {
+        Some(limit) => I::from_body_limited(body, limit),
+        None => I::from_body(body),
+    };
+    let body = match body {
         Ok(body) => body,
         Err(err) => {
             eprintln!("Errored during body parsing: {err}");
 
-            let res = json_error_response(StatusCode::BAD_REQUEST, err);
+            let status_code = err
+                .downcast_ref::<HttpError>()
+                .map(|err| err.status_code)
+                .unwrap_or(StatusCode::BAD_REQUEST);
+            let res = json_error_response(status_code, err);
             response_out.send(res).expect("Failed to send response");
             return;
         }
@@ -37,30 +62,96 @@ where
         Err(err) => {
             eprintln!("Errored during request handling: {err}");
 
-            let res = json_error_response(StatusCode::INTERNAL_SERVER_ERROR, err);
+            let status_code = err
+                .downcast_ref::<HttpError>()
+                .map(|err| err.status_code)
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            let res = json_error_response(status_code, err);
             response_out.send(res).expect("Failed to send response");
             return;
         }
     };
 
+    let res = into_bytes_response(res).unwrap();
+    response_out.send(res).expect("Failed to send response");
+}
+
+// parses the body of a request read as bytes, answering with a 400 when invalid
+pub fn parse_request<I: FromBody>(req: Request<Bytes>) -> Result<Request<I>> {
+    let (parts, body) = req.into_parts();
+    let body = I::from_data(body)
+        .map_err(|err| HttpError::bad_request(format!("Invalid request body: {err}")))?;
+    Ok(Request::from_parts(parts, body))
+}
+
+// converts a typed response into bytes, like `run` does before sending it
+pub fn into_bytes_response<O: IntoBody>(res: Response<O>) -> Result<Response<Bytes>> {
     let (mut parts, data) = res.into_parts();
     data.extend_response_parts(&mut parts);
-    let body = data.into_body().unwrap();
-    let res = Response::from_parts(parts, body);
+    let body = data.into_body()?;
+    Ok(Response::from_parts(parts, body))
+}
 
-    response_out.send(res).expect("Failed to send response");
+/*
+ * Error carrying the HTTP status code to respond with.
+ * Any other error returned by a handler is answered with a 500.
+ */
+#[derive(Debug)]
+pub struct HttpError {
+    pub status_code: StatusCode,
+    pub message: String,
+    // extra fields added to the JSON error body
+    pub details: serde_json::Map<String, serde_json::Value>,
+}
+
+impl HttpError {
+    pub fn new(status_code: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status_code,
+            message: message.into(),
+            details: serde_json::Map::new(),
+        }
+    }
+
+    pub fn with_detail(mut self, key: &str, value: serde_json::Value) -> Self {
+        self.details.insert(key.to_string(), value);
+        self
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    // any other error becomes a 500, like in `run`
+    pub fn from_error(err: anyhow::Error) -> Self {
+        match err.downcast::<HttpError>() {
+            Ok(http_err) => http_err,
+            Err(err) => Self::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
 }
 
+impl std::error::Error for HttpError {}
+
 fn json_error_response(status_code: StatusCode, err: anyhow::Error) -> Response<Bytes> {
+    let mut body = serde_json::json!({
+        "error": err.to_string(),
+    });
+    if let Some(http_err) = err.downcast_ref::<HttpError>() {
+        for (key, value) in &http_err.details {
+            body[key] = value.clone();
+        }
+    }
+
     Response::builder()
         .status(status_code)
-        .body(
-            Json(serde_json::json!({
-                "error": err.to_string(),
-            }))
-            .into_body()
-            .unwrap(),
-        )
+        .body(Json(body).into_body().unwrap())
         .unwrap()
 }
 
@@ -93,6 +184,61 @@ mod tests {
         assert!(body_str.contains("\"error\":\"something went wrong\""));
     }
 
+    #[test]
+    fn test_http_error_downcast() {
+        let err: anyhow::Error = HttpError::bad_request("invalid role").into();
+        assert_eq!(err.to_string(), "invalid role");
+
+        let http_err = err.downcast_ref::<HttpError>().unwrap();
+        assert_eq!(http_err.status_code, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_http_error_from_error() {
+        let err = HttpError::from_error(HttpError::bad_request("invalid role").into());
+        assert_eq!(err.status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(err.message, "invalid role");
+
+        let err = HttpError::from_error(anyhow::anyhow!("something went wrong"));
+        assert_eq!(err.status_code, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(err.message, "something went wrong");
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let req = Request::new(Bytes::from(r#"{"x": 1}"#));
+        let req: Request<Json<serde_json::Value>> = parse_request(req).unwrap();
+        assert_eq!(req.body().0["x"], 1);
+
+        let err =
+            parse_request::<Json<serde_json::Value>>(Request::new(Bytes::from("{"))).unwrap_err();
+        let http_err = err.downcast_ref::<HttpError>().unwrap();
+        assert_eq!(http_err.status_code, StatusCode::BAD_REQUEST);
+        assert!(http_err.message.starts_with("Invalid request body"));
+    }
+
+    #[test]
+    fn test_into_bytes_response() {
+        let res = Response::builder()
+            .status(StatusCode::CREATED)
+            .body(Json(serde_json::json!({"ok": true})))
+            .unwrap();
+        let res = into_bytes_response(res).unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(res.body(), &Bytes::from(r#"{"ok":true}"#));
+    }
+
+    #[test]
+    fn test_json_error_response_with_details() {
+        let err = HttpError::new(StatusCode::UNPROCESSABLE_ENTITY, "flagged")
+            .with_detail("categories", serde_json::json!(["violence"]));
+        let response = json_error_response(StatusCode::UNPROCESSABLE_ENTITY, err.into());
+
+        let body_str = std::str::from_utf8(response.body()).unwrap();
+        assert_eq!(body_str, r#"{"categories":["violence"],"error":"flagged"}"#);
+    }
+
     #[test]
     fn test_json_error_response_empty_error() {
         let err = anyhow::anyhow!("");
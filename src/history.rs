@@ -0,0 +1,352 @@
+use std::str::FromStr;
+
+use crate::openai_payload::{Message, Role};
+
+pub(crate) const DEFAULT_CHARS_PER_TOKEN: f32 = 4.0;
+pub(crate) const DROPPED_MESSAGES_HEADER: &str = "x-llm-dropped-messages";
+pub(crate) const SUMMARIZED_MESSAGES_HEADER: &str = "x-llm-summarized-messages";
+pub(crate) const DEFAULT_SUMMARY_PROMPT: &str = "Summarize the following conversation in a few sentences. Keep the facts, names and decisions needed to continue it.";
+pub(crate) const DEFAULT_SUMMARY_RECENT_MESSAGES: usize = 4;
+
+// tokens added by the chat format around each message, and to prime the reply
+const TOKENS_PER_MESSAGE: usize = 4;
+const TOKENS_PER_REPLY: usize = 3;
+
+/*
+ * Decides which messages are removed when the conversation
+ * doesn't fit in `max_input_tokens`.
+ * System messages and the last message are never removed.
+ */
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryStrategy {
+    // drop the oldest messages first
+    #[default]
+    DropOldest,
+    // keep the first message of the conversation and drop the ones following it
+    ElideMiddle,
+    // replace the oldest messages by a summary generated by the model
+    Summarize,
+}
+
+impl FromStr for HistoryStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "elide_middle" => Ok(Self::ElideMiddle),
+            "summarize" => Ok(Self::Summarize),
+            _ => Err(anyhow::anyhow!(
+                "Invalid history_strategy setting: '{value}'"
+            )),
+        }
+    }
+}
+
+/*
+ * Approximates the number of tokens of a conversation from its length in characters.
+ * This is intentionally cheap: it avoids embedding a BPE vocabulary in the component.
+ */
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TokenEstimator {
+    chars_per_token: f32,
+}
+
+impl TokenEstimator {
+    pub(crate) fn new(chars_per_token: f32) -> Self {
+        let chars_per_token = if chars_per_token > 0.0 {
+            chars_per_token
+        } else {
+            DEFAULT_CHARS_PER_TOKEN
+        };
+        TokenEstimator { chars_per_token }
+    }
+
+    pub(crate) fn estimate_text(&self, text: &str) -> usize {
+        (text.chars().count() as f32 / self.chars_per_token).ceil() as usize
+    }
+
+    pub(crate) fn estimate_message(&self, message: &Message) -> usize {
+        TOKENS_PER_MESSAGE + self.estimate_text(&message.content)
+    }
+
+    pub(crate) fn estimate_messages(&self, messages: &[Message]) -> usize {
+        TOKENS_PER_REPLY
+            + messages
+                .iter()
+                .map(|m| self.estimate_message(m))
+                .sum::<usize>()
+    }
+}
+
+impl Default for TokenEstimator {
+    fn default() -> Self {
+        TokenEstimator::new(DEFAULT_CHARS_PER_TOKEN)
+    }
+}
+
+impl HistoryStrategy {
+    // returns the remaining messages and how many were dropped
+    pub(crate) fn truncate(
+        &self,
+        mut messages: Vec<Message>,
+        max_input_tokens: usize,
+        estimator: &TokenEstimator,
+    ) -> (Vec<Message>, usize) {
+        let mut total = estimator.estimate_messages(&messages);
+        let mut dropped = 0;
+
+        while total > max_input_tokens {
+            let Some(index) = self.next_to_drop(&messages) else {
+                break;
+            };
+            total -= estimator.estimate_message(&messages.remove(index));
+            dropped += 1;
+        }
+
+        (messages, dropped)
+    }
+
+    fn next_to_drop(&self, messages: &[Message]) -> Option<usize> {
+        let last = messages.len().checked_sub(1)?;
+        let mut candidates = messages
+            .iter()
+            .enumerate()
+            .filter(|(index, m)| m.role != Role::System && *index != last)
+            .map(|(index, _)| index);
+
+        match self {
+            // summarized conversations that still don't fit lose their oldest messages
+            Self::DropOldest | Self::Summarize => candidates.next(),
+            Self::ElideMiddle => candidates.nth(1),
+        }
+    }
+}
+
+/*
+ * Conversation split in the parts used by the summarize strategy:
+ * system messages are kept, older messages are summarized and recent messages are sent as-is.
+ */
+pub(crate) struct SummarySplit {
+    pub(crate) system: Vec<Message>,
+    pub(crate) older: Vec<Message>,
+    pub(crate) recent: Vec<Message>,
+}
+
+impl SummarySplit {
+    // returns None when there is nothing old enough to summarize
+    pub(crate) fn new(messages: Vec<Message>, recent_messages: usize) -> Option<Self> {
+        let (system, mut older): (Vec<Message>, Vec<Message>) =
+            messages.into_iter().partition(|m| m.role == Role::System);
+        // always keep at least the last message
+        let recent_messages = recent_messages.max(1);
+        if older.len() <= recent_messages {
+            return None;
+        }
+        let recent = older.split_off(older.len() - recent_messages);
+        Some(SummarySplit {
+            system,
+            older,
+            recent,
+        })
+    }
+
+    // the older messages, as a plain text transcript given to the summarization model
+    pub(crate) fn transcript(&self) -> String {
+        self.older
+            .iter()
+            .map(|m| format!("{}: {}", m.role, m.content))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    pub(crate) fn with_summary(self, summary: &str) -> Vec<Message> {
+        let mut messages = self.system;
+        messages.push(Message {
+            role: Role::System,
+            content: format!("Summary of the earlier conversation: {summary}"),
+        });
+        messages.extend(self.recent);
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            message(Role::System, "system"),
+            message(Role::User, "first question"),
+            message(Role::Assistant, "first answer"),
+            message(Role::User, "second question"),
+            message(Role::Assistant, "second answer"),
+            message(Role::User, "last question"),
+        ]
+    }
+
+    fn contents(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_str()).collect()
+    }
+
+    #[test]
+    fn test_history_strategy_from_str() {
+        assert_eq!(
+            "drop_oldest".parse::<HistoryStrategy>().unwrap(),
+            HistoryStrategy::DropOldest
+        );
+        assert_eq!(
+            "elide_middle".parse::<HistoryStrategy>().unwrap(),
+            HistoryStrategy::ElideMiddle
+        );
+        assert_eq!(
+            "summarize".parse::<HistoryStrategy>().unwrap(),
+            HistoryStrategy::Summarize
+        );
+        assert_eq!(
+            "oldest".parse::<HistoryStrategy>().unwrap_err().to_string(),
+            "Invalid history_strategy setting: 'oldest'"
+        );
+    }
+
+    #[test]
+    fn test_estimate_text() {
+        let estimator = TokenEstimator::new(4.0);
+        assert_eq!(estimator.estimate_text(""), 0);
+        assert_eq!(estimator.estimate_text("abcd"), 1);
+        assert_eq!(estimator.estimate_text("abcde"), 2);
+    }
+
+    #[test]
+    fn test_estimate_messages() {
+        let estimator = TokenEstimator::new(1.0);
+        let messages = vec![message(Role::User, "abc"), message(Role::Assistant, "de")];
+        assert_eq!(
+            estimator.estimate_messages(&messages),
+            3 + (4 + 3) + (4 + 2)
+        );
+    }
+
+    #[test]
+    fn test_estimator_invalid_ratio_uses_default() {
+        let estimator = TokenEstimator::new(0.0);
+        assert_eq!(estimator.estimate_text("abcdefgh"), 2);
+    }
+
+    #[test]
+    fn test_truncate_not_needed() {
+        let estimator = TokenEstimator::default();
+        let (messages, dropped) =
+            HistoryStrategy::DropOldest.truncate(conversation(), 1000, &estimator);
+        assert_eq!(messages.len(), 6);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn test_truncate_drop_oldest() {
+        let estimator = TokenEstimator::new(1.0);
+        let budget = estimator.estimate_messages(&conversation()) - 1;
+        let (messages, dropped) =
+            HistoryStrategy::DropOldest.truncate(conversation(), budget, &estimator);
+        assert_eq!(dropped, 1);
+        assert_eq!(
+            contents(&messages),
+            vec![
+                "system",
+                "first answer",
+                "second question",
+                "second answer",
+                "last question"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate_elide_middle() {
+        let estimator = TokenEstimator::new(1.0);
+        let budget = estimator.estimate_messages(&conversation()) - 1;
+        let (messages, dropped) =
+            HistoryStrategy::ElideMiddle.truncate(conversation(), budget, &estimator);
+        assert_eq!(dropped, 1);
+        assert_eq!(
+            contents(&messages),
+            vec![
+                "system",
+                "first question",
+                "second question",
+                "second answer",
+                "last question"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_truncate_keeps_system_and_last_message() {
+        let estimator = TokenEstimator::new(1.0);
+        let (messages, dropped) =
+            HistoryStrategy::DropOldest.truncate(conversation(), 0, &estimator);
+        assert_eq!(dropped, 4);
+        assert_eq!(contents(&messages), vec!["system", "last question"]);
+
+        let (messages, dropped) =
+            HistoryStrategy::ElideMiddle.truncate(conversation(), 0, &estimator);
+        assert_eq!(dropped, 3);
+        assert_eq!(
+            contents(&messages),
+            vec!["system", "first question", "last question"]
+        );
+    }
+
+    #[test]
+    fn test_summary_split() {
+        let split = SummarySplit::new(conversation(), 2).unwrap();
+        assert_eq!(contents(&split.system), vec!["system"]);
+        assert_eq!(
+            contents(&split.older),
+            vec!["first question", "first answer", "second question"]
+        );
+        assert_eq!(
+            contents(&split.recent),
+            vec!["second answer", "last question"]
+        );
+    }
+
+    #[test]
+    fn test_summary_split_nothing_to_summarize() {
+        assert!(SummarySplit::new(conversation(), 5).is_none());
+        assert!(SummarySplit::new(vec![message(Role::User, "hello")], 0).is_none());
+    }
+
+    #[test]
+    fn test_summary_split_transcript() {
+        let split = SummarySplit::new(conversation(), 3).unwrap();
+        assert_eq!(
+            split.transcript(),
+            "user: first question\nassistant: first answer"
+        );
+    }
+
+    #[test]
+    fn test_summary_split_with_summary() {
+        let split = SummarySplit::new(conversation(), 1).unwrap();
+        let messages = split.with_summary("They talked.");
+        assert_eq!(
+            contents(&messages),
+            vec![
+                "system",
+                "Summary of the earlier conversation: They talked.",
+                "last question"
+            ]
+        );
+        assert_eq!(messages[1].role, Role::System);
+    }
+}
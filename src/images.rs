@@ -0,0 +1,281 @@
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::HttpError;
+use crate::openai_payload::generate_endpoint;
+use crate::redaction::Redactor;
+
+pub(crate) const DEFAULT_IMAGE_MODEL: &str = "dall-e-3";
+pub(crate) const DEFAULT_IMAGE_SIZE: &str = "1024x1024";
+pub(crate) const DEFAULT_MAX_IMAGES: u32 = 1;
+const ENDPOINT: &str = "/v1/images/generations";
+// the upstream rejects longer prompts
+const MAX_PROMPT_CHARS: usize = 4000;
+const QUALITIES: [&str; 6] = ["standard", "hd", "low", "medium", "high", "auto"];
+const RESPONSE_FORMATS: [&str; 2] = ["url", "b64_json"];
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct ImagePayload {
+    model: String,
+    prompt: String,
+    n: u32,
+    size: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quality: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<String>,
+}
+
+impl ImagePayload {
+    /*
+     * Validates the client's request body against the settings:
+     * the size must be one of `allowed_sizes`, the first one by default, and `n` at most `max_images`.
+     */
+    pub(crate) fn from_request_body(
+        model: String,
+        body: &serde_json::Value,
+        allowed_sizes: &[String],
+        max_images: u32,
+    ) -> Result<Self, HttpError> {
+        let prompt = match body.get("prompt") {
+            Some(serde_json::Value::String(prompt))
+                if !prompt.trim().is_empty() && prompt.chars().count() <= MAX_PROMPT_CHARS =>
+            {
+                prompt.clone()
+            }
+            Some(_) => {
+                return Err(HttpError::bad_request(format!(
+                    "Invalid prompt, expected a string of 1 to {MAX_PROMPT_CHARS} characters"
+                )))
+            }
+            None => {
+                return Err(HttpError::bad_request(
+                    "Missing 'prompt' field in request body",
+                ))
+            }
+        };
+        let n = match body.get("n") {
+            None | Some(serde_json::Value::Null) => 1,
+            Some(n) => n
+                .as_u64()
+                .filter(|n| *n > 0)
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| HttpError::bad_request(format!("Invalid n {n}")))?,
+        };
+        if n > max_images {
+            return Err(HttpError::bad_request(format!(
+                "At most {max_images} images can be generated per request"
+            )));
+        }
+        let size = match body.get("size") {
+            None | Some(serde_json::Value::Null) => allowed_sizes
+                .first()
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_IMAGE_SIZE.to_string()),
+            Some(serde_json::Value::String(size)) if allowed_sizes.contains(size) => size.clone(),
+            Some(size) => {
+                return Err(HttpError::bad_request(format!(
+                    "Size {size} is not allowed"
+                )))
+            }
+        };
+        let quality = optional_choice(body, "quality", &QUALITIES)?;
+        let response_format = optional_choice(body, "response_format", &RESPONSE_FORMATS)?;
+        Ok(ImagePayload {
+            model,
+            prompt,
+            n,
+            size,
+            quality,
+            response_format,
+        })
+    }
+
+    pub(crate) fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    pub(crate) fn redact(&mut self, redactor: &mut Redactor) {
+        self.prompt = redactor.redact(&self.prompt);
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        hostname: Option<String>,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(self)?)),
+        )
+    }
+}
+
+fn optional_choice(
+    body: &serde_json::Value,
+    key: &str,
+    choices: &[&str],
+) -> Result<Option<String>, HttpError> {
+    match body.get(key) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(value)) if choices.contains(&value.as_str()) => {
+            Ok(Some(value.clone()))
+        }
+        Some(value) => Err(HttpError::bad_request(format!("Invalid {key} {value}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Mock send method to avoid real HTTP call, answering with one URL per image,
+    // and rejecting the "upstream error" prompt
+    pub struct MockImageResponse {
+        status: u16,
+        headers: http::HeaderMap,
+        body: serde_json::Value,
+    }
+    impl MockImageResponse {
+        pub fn status_code(&self) -> u16 {
+            self.status
+        }
+        pub fn headers(&self) -> &http::HeaderMap {
+            &self.headers
+        }
+        pub fn body(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(self.body.to_string().into())
+        }
+    }
+
+    impl ImagePayload {
+        pub fn send(
+            &self,
+            _hostname: Option<String>,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockImageResponse> {
+            // dall-e-3 answers with the prompt it used, which tells what was sent
+            let image = |index| match self.response_format.as_deref() {
+                Some("b64_json") => {
+                    json!({ "b64_json": "aW1hZ2U=", "revised_prompt": self.prompt })
+                }
+                _ => json!({
+                    "url": format!("https://images.example.com/{index}.png"),
+                    "revised_prompt": self.prompt
+                }),
+            };
+            if self.prompt == "upstream error" {
+                return Ok(MockImageResponse {
+                    status: 400,
+                    headers: http::HeaderMap::new(),
+                    body: serde_json::json!({"error": {"message": "Rejected upstream", "type": "invalid_request_error"}}),
+                });
+            }
+            Ok(MockImageResponse {
+                status: 200,
+                headers: http::HeaderMap::new(),
+                body: json!({
+                    "created": 1700000000,
+                    "data": (0..self.n).map(image).collect::<Vec<_>>(),
+                }),
+            })
+        }
+    }
+
+    fn parse_payload(body: serde_json::Value) -> Result<ImagePayload, HttpError> {
+        ImagePayload::from_request_body(
+            DEFAULT_IMAGE_MODEL.to_string(),
+            &body,
+            &["1024x1024".to_string(), "1792x1024".to_string()],
+            2,
+        )
+    }
+
+    #[test]
+    fn test_image_payload_serialization() {
+        let payload = parse_payload(json!({"prompt": "A red bicycle"})).unwrap();
+        assert_eq!(payload.prompt(), "A red bicycle");
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({"model": "dall-e-3", "prompt": "A red bicycle", "n": 1, "size": "1024x1024"})
+        );
+
+        let payload = parse_payload(json!({
+            "prompt": "A red bicycle",
+            "n": 2,
+            "size": "1792x1024",
+            "quality": "hd",
+            "response_format": "b64_json"
+        }))
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "model": "dall-e-3",
+                "prompt": "A red bicycle",
+                "n": 2,
+                "size": "1792x1024",
+                "quality": "hd",
+                "response_format": "b64_json"
+            })
+        );
+    }
+
+    #[test]
+    fn test_image_payload_redact() {
+        let mut payload = parse_payload(json!({"prompt": "A card for jane@example.com"})).unwrap();
+        payload.redact(&mut Redactor::new(&[crate::redaction::PiiKind::Email]));
+        assert_eq!(payload.prompt(), "A card for [EMAIL_1]");
+    }
+
+    #[test]
+    fn test_image_payload_limits() {
+        let err = parse_payload(json!({"prompt": "A red bicycle", "n": 3})).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "At most 2 images can be generated per request"
+        );
+        let err = parse_payload(json!({"prompt": "A red bicycle", "size": "256x256"})).unwrap_err();
+        assert_eq!(err.to_string(), r#"Size "256x256" is not allowed"#);
+    }
+
+    #[test]
+    fn test_image_payload_invalid() {
+        assert_eq!(
+            parse_payload(json!({})).unwrap_err().to_string(),
+            "Missing 'prompt' field in request body"
+        );
+        for body in [
+            json!({"prompt": ""}),
+            json!({"prompt": "Hi", "n": 0}),
+            json!({"prompt": "Hi", "quality": "ultra"}),
+            json!({"prompt": "Hi", "response_format": "png"}),
+        ] {
+            let err = parse_payload(body).unwrap_err();
+            assert_eq!(err.status_code, http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_image_generate_endpoint() {
+        let payload = parse_payload(json!({"prompt": "Hi"})).unwrap();
+        assert_eq!(
+            payload.generate_endpoint(None),
+            "https://api.openai.com/v1/images/generations"
+        );
+    }
+}
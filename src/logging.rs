@@ -0,0 +1,285 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::helpers::HttpError;
+use crate::openai_payload::{Message, Usage};
+use crate::redaction::{PiiKind, Redactor};
+
+/*
+ * How much of each chat exchange is written to the logs.
+ */
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    // only failed exchanges
+    Error,
+    // every exchange, without the prompt and completion text
+    #[default]
+    Info,
+    // every exchange, with the prompt and completion text
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(Self::Off),
+            "error" => Ok(Self::Error),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(anyhow::anyhow!("Invalid log_level setting: '{value}'")),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Default, PartialEq)]
+pub(crate) struct UsageLog {
+    pub(crate) prompt_tokens: u64,
+    pub(crate) cached_tokens: u64,
+    pub(crate) completion_tokens: u64,
+    pub(crate) reasoning_tokens: u64,
+}
+
+impl From<&Usage> for UsageLog {
+    fn from(usage: &Usage) -> Self {
+        UsageLog {
+            prompt_tokens: usage.prompt_tokens,
+            cached_tokens: usage.cached_tokens(),
+            completion_tokens: usage.completion_tokens,
+            reasoning_tokens: usage.reasoning_tokens(),
+        }
+    }
+}
+
+impl std::ops::AddAssign for UsageLog {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.cached_tokens += other.cached_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+    }
+}
+
+/*
+ * One structured log line per chat exchange, filled in as the request goes through the component.
+ */
+#[derive(serde::Serialize, Default)]
+pub(crate) struct ExchangeLog {
+    pub(crate) request_id: String,
+    pub(crate) provider: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) model: Option<String>,
+    pub(crate) status: u16,
+    pub(crate) latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) upstream_request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) upstream_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) usage: Option<UsageLog>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cost_usd: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) finish_reason: Option<String>,
+    // highest prompt injection score of the user messages, when detection is on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) injection_score: Option<f32>,
+    // the completion echoed the system prompt and was replaced by the refusal
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) suppressed_echo: bool,
+    // the component does not retry upstream calls yet
+    pub(crate) retry_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) prompt: Option<Vec<Message>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) completion: Option<String>,
+}
+
+impl ExchangeLog {
+    pub(crate) fn new(request_id: String, api_hostname: Option<&str>) -> Self {
+        ExchangeLog {
+            request_id,
+            provider: provider_name(api_hostname),
+            ..Default::default()
+        }
+    }
+
+    // adds the usage and cost of one upstream call, a chat request may make several,
+    // the cost stays unknown once one of the calls has no price
+    pub(crate) fn add_usage(&mut self, usage: &Usage, cost_usd: Option<f64>) {
+        match self.usage.as_mut() {
+            Some(total) => {
+                *total += UsageLog::from(usage);
+                self.cost_usd = self
+                    .cost_usd
+                    .zip(cost_usd)
+                    .map(|(total, cost)| total + cost);
+            }
+            None => {
+                self.usage = Some(UsageLog::from(usage));
+                self.cost_usd = cost_usd;
+            }
+        }
+    }
+
+    // the prompt and completion text, with the given kinds of personal data redacted
+    pub(crate) fn set_content(&mut self, prompt: &[Message], completion: &str, redact: &[PiiKind]) {
+        let mut redactor = Redactor::new(redact);
+        self.prompt = Some(
+            prompt
+                .iter()
+                .map(|message| Message {
+                    content: redactor.redact(&message.content),
+                    ..message.clone()
+                })
+                .collect(),
+        );
+        self.completion = Some(redactor.redact(completion));
+    }
+
+    // record the outcome of the exchange, errors are answered like `helpers::run` does
+    pub(crate) fn finish<B>(
+        &mut self,
+        result: &anyhow::Result<http::Response<B>>,
+        latency: Duration,
+    ) {
+        self.latency_ms = latency.as_millis() as u64;
+        match result {
+            Ok(response) => self.status = response.status().as_u16(),
+            Err(err) => {
+                self.status = err
+                    .downcast_ref::<HttpError>()
+                    .map(|err| err.status_code)
+                    .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR)
+                    .as_u16();
+                self.error = Some(err.to_string());
+            }
+        }
+    }
+
+    // the JSON line to write at this log level, if any
+    pub(crate) fn to_line(&self, level: LogLevel) -> Option<String> {
+        match level {
+            LogLevel::Off => None,
+            LogLevel::Error if self.status < 400 => None,
+            _ => serde_json::to_string(self).ok(),
+        }
+    }
+
+    pub(crate) fn emit(&self, level: LogLevel) {
+        if let Some(line) = self.to_line(level) {
+            eprintln!("{line}");
+        }
+    }
+}
+
+// the upstream API host, without scheme
+fn provider_name(api_hostname: Option<&str>) -> String {
+    api_hostname
+        .map(|host| host.trim_start_matches("https://").trim_end_matches('/'))
+        .unwrap_or("api.openai.com")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai_payload::Role;
+
+    #[test]
+    fn test_log_level_from_str() {
+        assert_eq!("off".parse::<LogLevel>().unwrap(), LogLevel::Off);
+        assert_eq!("error".parse::<LogLevel>().unwrap(), LogLevel::Error);
+        assert_eq!("info".parse::<LogLevel>().unwrap(), LogLevel::Info);
+        assert_eq!("debug".parse::<LogLevel>().unwrap(), LogLevel::Debug);
+        assert_eq!(
+            "trace".parse::<LogLevel>().unwrap_err().to_string(),
+            "Invalid log_level setting: 'trace'"
+        );
+    }
+
+    #[test]
+    fn test_provider_name() {
+        assert_eq!(provider_name(None), "api.openai.com");
+        assert_eq!(
+            provider_name(Some("https://my-proxy.example.com/")),
+            "my-proxy.example.com"
+        );
+    }
+
+    #[test]
+    fn test_exchange_log_finish_success() {
+        let mut log = ExchangeLog::new("req-1".to_string(), None);
+        let response = http::Response::builder().status(200).body(()).unwrap();
+        log.finish(&Ok(response), Duration::from_millis(42));
+        assert_eq!(log.status, 200);
+        assert_eq!(log.latency_ms, 42);
+        assert!(log.error.is_none());
+    }
+
+    #[test]
+    fn test_exchange_log_finish_error() {
+        let mut log = ExchangeLog::new("req-1".to_string(), None);
+        let result: anyhow::Result<http::Response<()>> =
+            Err(HttpError::bad_request("Invalid role").into());
+        log.finish(&result, Duration::ZERO);
+        assert_eq!(log.status, 400);
+        assert_eq!(log.error.as_deref(), Some("Invalid role"));
+
+        let result: anyhow::Result<http::Response<()>> = Err(anyhow::anyhow!("boom"));
+        log.finish(&result, Duration::ZERO);
+        assert_eq!(log.status, 500);
+    }
+
+    #[test]
+    fn test_exchange_log_to_line() {
+        let mut log = ExchangeLog::new("req-1".to_string(), None);
+        log.model = Some("gpt-4o".to_string());
+        log.status = 200;
+        log.latency_ms = 12;
+        log.usage = Some(UsageLog {
+            prompt_tokens: 10,
+            cached_tokens: 0,
+            completion_tokens: 5,
+            reasoning_tokens: 0,
+        });
+        log.finish_reason = Some("stop".to_string());
+
+        assert_eq!(
+            log.to_line(LogLevel::Info).unwrap(),
+            r#"{"request_id":"req-1","provider":"api.openai.com","model":"gpt-4o","status":200,"latency_ms":12,"usage":{"prompt_tokens":10,"cached_tokens":0,"completion_tokens":5,"reasoning_tokens":0},"finish_reason":"stop","retry_count":0}"#
+        );
+        assert!(log.to_line(LogLevel::Off).is_none());
+        assert!(log.to_line(LogLevel::Error).is_none());
+
+        log.injection_score = Some(0.5);
+        log.suppressed_echo = true;
+        assert!(log
+            .to_line(LogLevel::Info)
+            .unwrap()
+            .contains(r#""finish_reason":"stop","injection_score":0.5,"suppressed_echo":true,"#));
+
+        log.status = 502;
+        assert!(log.to_line(LogLevel::Error).is_some());
+    }
+
+    #[test]
+    fn test_exchange_log_set_content_redacted() {
+        let mut log = ExchangeLog::new("req-1".to_string(), None);
+        log.set_content(
+            &[Message {
+                role: Role::User,
+                content: "I'm jane@example.com".to_string(),
+            }],
+            "Hello jane@example.com",
+            &[PiiKind::Email],
+        );
+        assert_eq!(log.prompt.unwrap()[0].content, "I'm [EMAIL_1]");
+        assert_eq!(log.completion.as_deref(), Some("Hello [EMAIL_1]"));
+    }
+}
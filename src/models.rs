@@ -0,0 +1,148 @@
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::openai_payload::generate_endpoint;
+
+pub(crate) const DEFAULT_MODELS_CACHE_TTL_SECONDS: u64 = 300;
+const ENDPOINT: &str = "/v1/models";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct Model {
+    pub(crate) id: String,
+    #[serde(default = "default_object")]
+    pub(crate) object: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) created: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) owned_by: Option<String>,
+}
+
+fn default_object() -> String {
+    "model".to_string()
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ModelList {
+    pub(crate) data: Vec<Model>,
+}
+
+impl ModelList {
+    pub(crate) fn from_json_slice(response_body: &[u8]) -> Result<Self, anyhow::Error> {
+        serde_json::from_slice(response_body)
+            .map_err(|e| anyhow::anyhow!("Could not parse OpenAI models: {e}"))
+    }
+}
+
+pub(crate) struct ModelsRequest {
+    endpoint: String,
+}
+
+impl ModelsRequest {
+    pub(crate) fn new(hostname: Option<String>) -> Self {
+        ModelsRequest {
+            endpoint: generate_endpoint(hostname, ENDPOINT),
+        }
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Get,
+            &self.endpoint,
+            &api_key,
+            forwarded_headers,
+            None,
+        )
+    }
+}
+
+// the allowed models the upstream knows about, in the order they are configured
+pub(crate) fn filter_allowed(models: &[Model], allowed: &[String]) -> Vec<Model> {
+    allowed
+        .iter()
+        .filter_map(|id| models.iter().find(|model| model.id == *id))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Mock send method to avoid real HTTP call, failing for hosts starting with "down."
+    pub struct MockModelsResponse {
+        status_code: u16,
+        headers: http::HeaderMap,
+    }
+    impl MockModelsResponse {
+        pub fn status_code(&self) -> u16 {
+            self.status_code
+        }
+        pub fn headers(&self) -> &http::HeaderMap {
+            &self.headers
+        }
+        pub fn body(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(json!({
+                "object": "list",
+                "data": [
+                    {"id": "gpt-4o", "object": "model", "created": 1715367049, "owned_by": "system"},
+                    {"id": "gpt-4o-mini", "object": "model", "created": 1721172741, "owned_by": "system"},
+                    {"id": "dall-e-3", "object": "model", "created": 1698785189, "owned_by": "system"},
+                ]
+            })
+            .to_string()
+            .into())
+        }
+    }
+
+    impl ModelsRequest {
+        pub fn send(
+            &self,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockModelsResponse> {
+            let status_code = match self.endpoint.starts_with("https://down.") {
+                true => 503,
+                false => 200,
+            };
+            Ok(MockModelsResponse {
+                status_code,
+                headers: http::HeaderMap::new(),
+            })
+        }
+    }
+
+    fn model(id: &str) -> Model {
+        Model {
+            id: id.to_string(),
+            object: "model".to_string(),
+            created: None,
+            owned_by: None,
+        }
+    }
+
+    #[test]
+    fn test_model_list_from_json_slice() {
+        let list = ModelList::from_json_slice(br#"{"data": [{"id": "gpt-4o"}]}"#).unwrap();
+        assert_eq!(list.data, vec![model("gpt-4o")]);
+        assert!(ModelList::from_json_slice(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_filter_allowed() {
+        let models = vec![model("gpt-4o"), model("gpt-4o-mini"), model("o3")];
+        let allowed = vec!["o3".to_string(), "gpt-5".to_string(), "gpt-4o".to_string()];
+        assert_eq!(
+            filter_allowed(&models, &allowed),
+            vec![model("o3"), model("gpt-4o")]
+        );
+    }
+}
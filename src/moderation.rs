@@ -0,0 +1,262 @@
+use std::collections::{BTreeSet, HashMap};
+
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::HttpError;
+use crate::logging::ExchangeLog;
+use crate::openai_payload::generate_endpoint;
+use crate::telemetry::Tracer;
+use crate::{forwarded_headers, Settings};
+
+const ENDPOINT: &str = "/v1/moderations";
+
+#[derive(serde::Serialize, Clone)]
+pub(crate) struct ModerationPayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    input: Vec<String>,
+}
+
+impl ModerationPayload {
+    pub(crate) fn new(model: Option<String>, input: Vec<String>) -> Self {
+        ModerationPayload { model, input }
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        hostname: Option<String>,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(self)?)),
+        )
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ModerationResult {
+    #[serde(default)]
+    pub(crate) categories: HashMap<String, bool>,
+    #[serde(default)]
+    pub(crate) category_scores: HashMap<String, f64>,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ModerationResponse {
+    pub(crate) results: Vec<ModerationResult>,
+}
+
+impl ModerationResponse {
+    pub(crate) fn from_json_string(response_body: String) -> Result<Self, anyhow::Error> {
+        let moderation_response: ModerationResponse = serde_json::from_str(&response_body)?;
+        Ok(moderation_response)
+    }
+
+    /*
+     * A category with a configured threshold is flagged when its score reaches the threshold,
+     * otherwise the provider's own decision is used.
+     */
+    pub(crate) fn flagged_categories(&self, thresholds: &HashMap<String, f64>) -> Vec<String> {
+        let mut flagged = BTreeSet::new();
+        for result in &self.results {
+            for (category, score) in &result.category_scores {
+                if thresholds.get(category).is_some_and(|t| score >= t) {
+                    flagged.insert(category.clone());
+                }
+            }
+            for (category, &is_flagged) in &result.categories {
+                if is_flagged && !thresholds.contains_key(category) {
+                    flagged.insert(category.clone());
+                }
+            }
+        }
+        flagged.into_iter().collect()
+    }
+}
+
+// returns a 422 error listing the flagged categories when the inputs are not acceptable
+pub(crate) fn check(
+    settings: &Settings,
+    input: Vec<String>,
+    message: &str,
+    log: &ExchangeLog,
+    tracer: &mut Tracer,
+) -> anyhow::Result<()> {
+    if input.is_empty() {
+        return Ok(());
+    }
+
+    let payload = ModerationPayload::new(settings.moderation_model.clone(), input);
+    match &settings.moderation_model {
+        Some(model) => tracer.start_call(&format!("moderation {model}")),
+        None => tracer.start_call("moderation"),
+    }
+    tracer.set_attribute("gen_ai.system", "openai");
+    tracer.set_attribute("server.address", log.provider.as_str());
+    let response = payload.send(
+        settings.api_hostname.clone(),
+        settings.api_key.clone(),
+        &forwarded_headers(log, tracer)?,
+    )?;
+    let response_status = response.status_code();
+    let response_body = String::from_utf8_lossy(&response.body()?).to_string();
+    if !(200..300).contains(&response_status) {
+        return Err(anyhow::anyhow!(
+            "Moderation failed: OpenAI responded with status {response_status}"
+        ));
+    }
+    tracer.end_call(None);
+
+    let categories = ModerationResponse::from_json_string(response_body)
+        .map_err(|e| anyhow::anyhow!("Could not parse moderation response: {e}"))?
+        .flagged_categories(&settings.moderation_thresholds);
+    if categories.is_empty() {
+        return Ok(());
+    }
+
+    Err(
+        HttpError::new(http::StatusCode::UNPROCESSABLE_ENTITY, message)
+            .with_detail("categories", serde_json::json!(categories))
+            .into(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mock send method to avoid real HTTP call, flagging any input containing "flag me"
+    // and failing when the request id or trace context isn't forwarded
+    pub struct MockModerationResponse {
+        flagged: bool,
+    }
+    impl MockModerationResponse {
+        pub fn status_code(&self) -> u16 {
+            200
+        }
+        pub fn body(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(serde_json::json!({
+                "results": [{
+                    "flagged": self.flagged,
+                    "categories": { "violence": self.flagged, "hate": false },
+                    "category_scores": { "violence": if self.flagged { 0.9 } else { 0.1 }, "hate": 0.2 }
+                }]
+            })
+            .to_string()
+            .into())
+        }
+    }
+
+    impl ModerationPayload {
+        pub fn send(
+            &self,
+            _hostname: Option<String>,
+            _apikey: String,
+            forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockModerationResponse> {
+            for header in [
+                crate::CLIENT_REQUEST_ID_HEADER,
+                crate::telemetry::TRACEPARENT_HEADER,
+            ] {
+                anyhow::ensure!(
+                    forwarded_headers.contains_key(header),
+                    "{header} not forwarded"
+                );
+            }
+            Ok(MockModerationResponse {
+                flagged: self.input.iter().any(|i| i.contains("flag me")),
+            })
+        }
+    }
+
+    fn response(json: &str) -> ModerationResponse {
+        ModerationResponse::from_json_string(json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_moderation_payload_serialization() {
+        let payload = ModerationPayload::new(None, vec!["Hello".to_string()]);
+        assert_eq!(
+            serde_json::to_string(&payload).unwrap(),
+            r#"{"input":["Hello"]}"#
+        );
+
+        let payload = ModerationPayload::new(
+            Some("omni-moderation-latest".to_string()),
+            vec!["Hello".to_string()],
+        );
+        assert_eq!(
+            serde_json::to_string(&payload).unwrap(),
+            r#"{"model":"omni-moderation-latest","input":["Hello"]}"#
+        );
+    }
+
+    #[test]
+    fn test_moderation_generate_endpoint() {
+        let payload = ModerationPayload::new(None, vec![]);
+        assert_eq!(
+            payload.generate_endpoint(None),
+            "https://api.openai.com/v1/moderations"
+        );
+        assert_eq!(
+            payload.generate_endpoint(Some("custom.example.com".to_string())),
+            "https://custom.example.com/v1/moderations"
+        );
+    }
+
+    #[test]
+    fn test_moderation_response_invalid_json() {
+        let result = ModerationResponse::from_json_string(r#"{"id": "modr-1"}"#.to_string());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_flagged_categories_uses_provider_decision() {
+        let response = response(
+            r#"{"results": [{
+                "flagged": true,
+                "categories": {"violence": true, "hate": false, "harassment": true},
+                "category_scores": {"violence": 0.8, "hate": 0.1, "harassment": 0.6}
+            }]}"#,
+        );
+        assert_eq!(
+            response.flagged_categories(&HashMap::new()),
+            vec!["harassment", "violence"]
+        );
+    }
+
+    #[test]
+    fn test_flagged_categories_with_thresholds() {
+        let response = response(
+            r#"{"results": [{
+                "flagged": true,
+                "categories": {"violence": true, "hate": false},
+                "category_scores": {"violence": 0.6, "hate": 0.3}
+            }]}"#,
+        );
+        let thresholds = HashMap::from([("violence".to_string(), 0.7), ("hate".to_string(), 0.3)]);
+        assert_eq!(response.flagged_categories(&thresholds), vec!["hate"]);
+    }
+
+    #[test]
+    fn test_flagged_categories_not_flagged() {
+        let response = response(
+            r#"{"results": [{"flagged": false, "categories": {"violence": false}, "category_scores": {"violence": 0.01}}]}"#,
+        );
+        assert!(response.flagged_categories(&HashMap::new()).is_empty());
+    }
+}
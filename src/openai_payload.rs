@@ -3,28 +3,218 @@ use waki::Response;
 
 const DEFAULT_HOST: &str = "api.openai.com";
 const ENDPOINT: &str = "/v1/chat/completions";
+pub(crate) const REASONING_TOKENS_HEADER: &str = "x-llm-reasoning-tokens";
+// o-series and gpt-5 models take a reasoning effort and reject sampling parameters
+const REASONING_MODEL_PREFIXES: [&str; 4] = ["o1", "o3", "o4", "gpt-5"];
+
+pub(crate) fn generate_endpoint(hostname: Option<String>, path: &str) -> String {
+    // use provided hostname or default to DEFAULT_HOST
+    let hostname = hostname.unwrap_or(DEFAULT_HOST.to_string());
+    // append the endpoint path
+    let mut endpoint = format!("{hostname}{path}");
+    // ensure the endpoint starts with "https://"
+    if !endpoint.starts_with("https://") {
+        endpoint = format!("https://{endpoint}");
+    }
+    endpoint
+}
+
+// calls the upstream API with the forwarded headers and the API key, and the body if any with its content type
+#[cfg(not(test))]
+pub(crate) fn send_request(
+    method: waki::Method,
+    endpoint: &str,
+    api_key: &str,
+    forwarded_headers: &http::HeaderMap,
+    body: Option<(&str, Vec<u8>)>,
+) -> Result<Response, anyhow::Error> {
+    let mut request = waki::Client::new()
+        .request(method, endpoint)
+        .headers(
+            forwarded_headers
+                .iter()
+                .map(|(name, value)| (name, value.clone())),
+        )
+        .header("Authorization", format!("Bearer {api_key}"));
+    if let Some((content_type, body)) = body {
+        request = request.header("Content-Type", content_type).body(body);
+    }
+    request.send()
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    Developer,
+    User,
+    Assistant,
+    Tool,
+}
+
+impl Role {
+    pub(crate) const ALL: [Role; 5] = [
+        Role::System,
+        Role::Developer,
+        Role::User,
+        Role::Assistant,
+        Role::Tool,
+    ];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::Developer => "developer",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == value)
+            .ok_or_else(|| anyhow::anyhow!("Invalid role: '{value}'"))
+    }
+}
+
+impl std::fmt::Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/*
+ * The upstream API chat requests are sent to.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiStyle {
+    // `/v1/chat/completions`
+    #[default]
+    Chat,
+    // the legacy `/v1/completions`, the messages being rendered as a prompt
+    Completions,
+    // `/v1/responses`, the system messages being sent as instructions
+    Responses,
+}
+
+impl ApiStyle {
+    // the `gen_ai.operation.name` of the upstream call
+    pub(crate) fn operation_name(&self) -> &'static str {
+        match self {
+            ApiStyle::Chat | ApiStyle::Responses => "chat",
+            ApiStyle::Completions => "text_completion",
+        }
+    }
+}
+
+impl std::str::FromStr for ApiStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "chat" => Ok(Self::Chat),
+            "completions" => Ok(Self::Completions),
+            "responses" => Ok(Self::Responses),
+            _ => Err(anyhow::anyhow!("Invalid api_style setting: '{value}'")),
+        }
+    }
+}
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub(crate) struct Message {
-    pub(crate) role: String,
+    pub(crate) role: Role,
     pub(crate) content: String,
 }
 
 impl Message {
     pub(crate) fn default_error_message() -> Self {
+        Message::refusal("An error occurred")
+    }
+
+    // canned reply returned instead of the model's answer, stored in sessions like one
+    pub(crate) fn refusal(content: &str) -> Self {
         Message {
-            role: "system".into(),
-            content: "An error occurred".into(),
+            role: Role::Assistant,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+    Minimal,
+    Low,
+    Medium,
+    High,
+}
+
+impl std::str::FromStr for ReasoningEffort {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "minimal" => Ok(Self::Minimal),
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            _ => Err(anyhow::anyhow!(
+                "Invalid reasoning_effort setting: '{value}'"
+            )),
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    Low,
+    Medium,
+    High,
+}
+
+impl std::str::FromStr for Verbosity {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            _ => Err(anyhow::anyhow!("Invalid verbosity setting: '{value}'")),
         }
     }
 }
 
+// a family name like `o3` matches `o3-mini` and `o3-2025-04-16`, but not `o30`
+pub(crate) fn is_reasoning_model(model: &str) -> bool {
+    REASONING_MODEL_PREFIXES.iter().any(|prefix| {
+        model == *prefix
+            || model
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('-'))
+    })
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub(crate) struct OpenAIPayload {
     model: String,
     messages: Vec<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning_effort: Option<ReasoningEffort>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verbosity: Option<Verbosity>,
 }
 
 impl OpenAIPayload {
@@ -37,19 +227,39 @@ impl OpenAIPayload {
             model,
             messages,
             max_completion_tokens,
+            temperature: None,
+            reasoning_effort: None,
+            verbosity: None,
         }
     }
 
-    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
-        // use provided hostname or default to DEFAULT_HOST
-        let hostname = hostname.unwrap_or(DEFAULT_HOST.to_string());
-        // append the endpoint path
-        let mut endpoint = format!("{hostname}{ENDPOINT}");
-        // ensure the endpoint starts with "https://"
-        if !endpoint.starts_with("https://") {
-            endpoint = format!("https://{endpoint}");
+    pub(crate) fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /*
+     * Reasoning models take developer messages instead of system messages
+     * and reject the temperature, so the payload is adapted to them.
+     */
+    pub(crate) fn for_reasoning_model(
+        mut self,
+        reasoning_effort: Option<ReasoningEffort>,
+        verbosity: Option<Verbosity>,
+    ) -> Self {
+        for message in self.messages.iter_mut() {
+            if message.role == Role::System {
+                message.role = Role::Developer;
+            }
         }
-        endpoint
+        self.temperature = None;
+        self.reasoning_effort = reasoning_effort;
+        self.verbosity = verbosity;
+        self
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
     }
 
     #[cfg(not(test))]
@@ -57,26 +267,71 @@ impl OpenAIPayload {
         &self,
         hostname: Option<String>,
         api_key: String,
+        forwarded_headers: &http::HeaderMap,
     ) -> Result<Response, anyhow::Error> {
-        let client = waki::Client::new();
-        let response = client
-            .post(&self.generate_endpoint(hostname))
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {api_key}"))
-            .body(serde_json::to_vec(self)?)
-            .send()?;
-        Ok(response)
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(self)?)),
+        )
     }
 }
 
 #[derive(serde::Deserialize)]
 pub(crate) struct OpenAIChoice {
     pub(crate) message: Message,
+    #[serde(default)]
+    pub(crate) finish_reason: Option<String>,
+}
+
+#[derive(serde::Deserialize, Default)]
+pub(crate) struct PromptTokensDetails {
+    #[serde(default)]
+    pub(crate) cached_tokens: u64,
+}
+
+#[derive(serde::Deserialize, Default)]
+pub(crate) struct CompletionTokensDetails {
+    #[serde(default)]
+    pub(crate) reasoning_tokens: u64,
+}
+
+#[derive(serde::Deserialize, Default)]
+pub(crate) struct Usage {
+    #[serde(default)]
+    pub(crate) prompt_tokens: u64,
+    #[serde(default)]
+    pub(crate) completion_tokens: u64,
+    #[serde(default)]
+    pub(crate) prompt_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default)]
+    pub(crate) completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+impl Usage {
+    pub(crate) fn cached_tokens(&self) -> u64 {
+        self.prompt_tokens_details
+            .as_ref()
+            .map_or(0, |details| details.cached_tokens)
+    }
+
+    // part of the completion tokens, billed but not in the reply
+    pub(crate) fn reasoning_tokens(&self) -> u64 {
+        self.completion_tokens_details
+            .as_ref()
+            .map_or(0, |details| details.reasoning_tokens)
+    }
 }
 
 #[derive(serde::Deserialize)]
 pub(crate) struct OpenAIResponse {
+    #[serde(default)]
+    pub(crate) id: Option<String>,
     pub(crate) choices: Vec<OpenAIChoice>,
+    #[serde(default)]
+    pub(crate) usage: Option<Usage>,
 }
 
 /*
@@ -90,13 +345,13 @@ impl OpenAIResponse {
         Ok(openai_response)
     }
 
-    pub(crate) fn first_choice_to_json(&self) -> serde_json::Value {
+    pub(crate) fn first_choice_to_json(&self, fallback: Message) -> serde_json::Value {
         // convert the first choice's message content to a string
         if let Some(choice) = self.choices.first() {
             serde_json::json!(&choice.message)
         } else {
             // fallback message (this should not happen, but just in case)
-            serde_json::json!(Message::default_error_message())
+            serde_json::json!(fallback)
         }
     }
 }
@@ -108,22 +363,137 @@ mod tests {
     #[test]
     fn test_message_struct() {
         let msg = Message {
-            role: "user".to_string(),
+            role: Role::User,
             content: "Hello!".to_string(),
         };
-        assert_eq!(msg.role, "user");
+        assert_eq!(msg.role, Role::User);
         assert_eq!(msg.content, "Hello!");
     }
 
+    #[test]
+    fn test_role_from_str() {
+        assert_eq!("system".parse::<Role>().unwrap(), Role::System);
+        assert_eq!("developer".parse::<Role>().unwrap(), Role::Developer);
+        assert_eq!("user".parse::<Role>().unwrap(), Role::User);
+        assert_eq!("assistant".parse::<Role>().unwrap(), Role::Assistant);
+        assert_eq!("tool".parse::<Role>().unwrap(), Role::Tool);
+    }
+
+    #[test]
+    fn test_api_style_from_str() {
+        assert_eq!("chat".parse::<ApiStyle>().unwrap(), ApiStyle::Chat);
+        assert_eq!(
+            "completions".parse::<ApiStyle>().unwrap(),
+            ApiStyle::Completions
+        );
+        assert_eq!(
+            "responses".parse::<ApiStyle>().unwrap(),
+            ApiStyle::Responses
+        );
+        assert_eq!(
+            "legacy".parse::<ApiStyle>().unwrap_err().to_string(),
+            "Invalid api_style setting: 'legacy'"
+        );
+    }
+
+    #[test]
+    fn test_is_reasoning_model() {
+        assert!(is_reasoning_model("o1"));
+        assert!(is_reasoning_model("o3-mini"));
+        assert!(is_reasoning_model("o4-mini-2025-04-16"));
+        assert!(is_reasoning_model("gpt-5-nano"));
+        assert!(!is_reasoning_model("gpt-4o"));
+        assert!(!is_reasoning_model("o30"));
+    }
+
+    #[test]
+    fn test_openai_payload_for_reasoning_model() {
+        let messages = vec![
+            Message {
+                role: Role::System,
+                content: "Be brief.".to_string(),
+            },
+            Message {
+                role: Role::User,
+                content: "Hi".to_string(),
+            },
+        ];
+        let payload = OpenAIPayload::new("o3".to_string(), messages.clone(), Some(100))
+            .with_temperature(Some(0.2))
+            .for_reasoning_model(Some(ReasoningEffort::High), Some(Verbosity::Low));
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::json!({
+                "model": "o3",
+                "messages": [
+                    {"role": "developer", "content": "Be brief."},
+                    {"role": "user", "content": "Hi"}
+                ],
+                "max_completion_tokens": 100,
+                "reasoning_effort": "high",
+                "verbosity": "low"
+            })
+        );
+
+        let payload =
+            OpenAIPayload::new("gpt-4o".to_string(), messages, None).with_temperature(Some(0.2));
+        let value = serde_json::to_value(&payload).unwrap();
+        assert_eq!(value["messages"][0]["role"], "system");
+        assert!((value["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_reasoning_settings_from_str() {
+        assert_eq!(
+            "minimal".parse::<ReasoningEffort>().unwrap(),
+            ReasoningEffort::Minimal
+        );
+        assert_eq!("high".parse::<Verbosity>().unwrap(), Verbosity::High);
+        assert_eq!(
+            "max".parse::<ReasoningEffort>().unwrap_err().to_string(),
+            "Invalid reasoning_effort setting: 'max'"
+        );
+        assert!("minimal".parse::<Verbosity>().is_err());
+    }
+
+    #[test]
+    fn test_role_from_str_invalid() {
+        let result = "admin".parse::<Role>();
+        assert_eq!(result.unwrap_err().to_string(), "Invalid role: 'admin'");
+    }
+
+    #[test]
+    fn test_openai_response_from_json_string_invalid_role() {
+        let json = r#"{"choices": [ { "message": { "role": "admin", "content": "Hi" } } ]}"#;
+        let result = OpenAIResponse::from_json_string(json.to_string());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_openai_response_usage() {
+        let json = r#"{
+            "choices": [{"message": {"role": "assistant", "content": "Hi"}}],
+            "usage": {"prompt_tokens": 20, "completion_tokens": 5, "total_tokens": 25,
+                      "prompt_tokens_details": {"cached_tokens": 8},
+                      "completion_tokens_details": {"reasoning_tokens": 3}}
+        }"#;
+        let response = OpenAIResponse::from_json_string(json.to_string()).unwrap();
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 20);
+        assert_eq!(usage.completion_tokens, 5);
+        assert_eq!(usage.cached_tokens(), 8);
+        assert_eq!(usage.reasoning_tokens(), 3);
+    }
+
     #[test]
     fn test_openai_payload_new() {
         let messages = vec![
             Message {
-                role: "user".to_string(),
+                role: Role::User,
                 content: "Hi".to_string(),
             },
             Message {
-                role: "assistant".to_string(),
+                role: Role::Assistant,
                 content: "Hello!".to_string(),
             },
         ];
@@ -136,7 +506,7 @@ mod tests {
     #[test]
     fn test_openai_payload_serialization() {
         let messages = vec![Message {
-            role: "user".to_string(),
+            role: Role::User,
             content: "Test".to_string(),
         }];
         let payload = OpenAIPayload::new("gpt-4".to_string(), messages, None);
@@ -157,7 +527,7 @@ mod tests {
         .to_string();
         let resp = OpenAIResponse::from_json_string(json).unwrap();
         assert_eq!(resp.choices.len(), 1);
-        assert_eq!(resp.choices[0].message.role, "assistant");
+        assert_eq!(resp.choices[0].message.role, Role::Assistant);
         assert_eq!(resp.choices[0].message.content, "Hi there!");
     }
 
@@ -178,14 +548,17 @@ mod tests {
     #[test]
     fn test_openai_response_to_response_with_choice() {
         let response = OpenAIResponse {
+            id: None,
             choices: vec![OpenAIChoice {
                 message: Message {
-                    role: "assistant".to_string(),
+                    role: Role::Assistant,
                     content: "Hello from OpenAI!".to_string(),
                 },
+                finish_reason: Some("stop".to_string()),
             }],
+            usage: None,
         };
-        let result = response.first_choice_to_json();
+        let result = response.first_choice_to_json(Message::default_error_message());
         // Should be a JSON string containing the message
         assert_eq!(result.get("role").unwrap().as_str(), Some("assistant"));
         assert_eq!(
@@ -196,11 +569,29 @@ mod tests {
 
     #[test]
     fn test_openai_response_to_response_no_choices() {
-        let response = OpenAIResponse { choices: vec![] };
-        let result = response.first_choice_to_json();
+        let response = OpenAIResponse {
+            id: None,
+            choices: vec![],
+            usage: None,
+        };
+        let result = response.first_choice_to_json(Message::default_error_message());
+        assert_eq!(
+            serde_json::to_string(&result).unwrap(),
+            r#"{"content":"An error occurred","role":"assistant"}"#
+        );
+    }
+
+    #[test]
+    fn test_openai_response_to_response_no_choices_refusal() {
+        let response = OpenAIResponse {
+            id: None,
+            choices: vec![],
+            usage: None,
+        };
+        let result = response.first_choice_to_json(Message::refusal("Sorry, I can't help."));
         assert_eq!(
             serde_json::to_string(&result).unwrap(),
-            r#"{"content":"An error occurred","role":"system"}"#
+            r#"{"content":"Sorry, I can't help.","role":"assistant"}"#
         );
     }
 
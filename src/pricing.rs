@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::openai_payload::Usage;
+
+pub(crate) const COST_HEADER: &str = "x-llm-cost-usd";
+const TOKENS_PER_PRICE_UNIT: f64 = 1_000_000.0;
+
+/*
+ * Price of a model in USD per million tokens.
+ */
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    pub cached_input: f64,
+}
+
+// built-in prices, overridable with the `model_prices` setting
+const DEFAULT_PRICES: [(&str, ModelPrice); 15] = [
+    ("gpt-3.5-turbo", ModelPrice::new(0.50, 1.50, 0.50)),
+    ("gpt-4", ModelPrice::new(30.00, 60.00, 30.00)),
+    ("gpt-4-turbo", ModelPrice::new(10.00, 30.00, 10.00)),
+    ("gpt-4o", ModelPrice::new(2.50, 10.00, 1.25)),
+    ("gpt-4o-mini", ModelPrice::new(0.15, 0.60, 0.075)),
+    ("gpt-4.1", ModelPrice::new(2.00, 8.00, 0.50)),
+    ("gpt-4.1-mini", ModelPrice::new(0.40, 1.60, 0.10)),
+    ("gpt-4.1-nano", ModelPrice::new(0.10, 0.40, 0.025)),
+    ("o1", ModelPrice::new(15.00, 60.00, 7.50)),
+    ("o3", ModelPrice::new(2.00, 8.00, 0.50)),
+    ("o3-mini", ModelPrice::new(1.10, 4.40, 0.55)),
+    ("o4-mini", ModelPrice::new(1.10, 4.40, 0.275)),
+    ("text-embedding-3-small", ModelPrice::new(0.02, 0.0, 0.02)),
+    ("text-embedding-3-large", ModelPrice::new(0.13, 0.0, 0.13)),
+    ("text-embedding-ada-002", ModelPrice::new(0.10, 0.0, 0.10)),
+];
+
+impl ModelPrice {
+    pub(crate) const fn new(input: f64, output: f64, cached_input: f64) -> Self {
+        ModelPrice {
+            input,
+            output,
+            cached_input,
+        }
+    }
+
+    pub(crate) fn cost(&self, input_tokens: u64, cached_tokens: u64, output_tokens: u64) -> f64 {
+        let uncached_tokens = input_tokens.saturating_sub(cached_tokens);
+        (uncached_tokens as f64 * self.input
+            + cached_tokens.min(input_tokens) as f64 * self.cached_input
+            + output_tokens as f64 * self.output)
+            / TOKENS_PER_PRICE_UNIT
+    }
+
+    pub(crate) fn usage_cost(&self, usage: &Usage) -> f64 {
+        self.cost(
+            usage.prompt_tokens,
+            usage.cached_tokens(),
+            usage.completion_tokens,
+        )
+    }
+}
+
+impl FromStr for ModelPrice {
+    type Err = anyhow::Error;
+
+    // `input/output[/cached_input]`, cached input defaults to the input price
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let prices = value
+            .split('/')
+            .map(|price| price.trim().parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| anyhow::anyhow!("Invalid model price: '{value}'"))?;
+        match prices[..] {
+            [input, output] => Ok(ModelPrice::new(input, output, input)),
+            [input, output, cached_input] => Ok(ModelPrice::new(input, output, cached_input)),
+            _ => Err(anyhow::anyhow!("Invalid model price: '{value}'")),
+        }
+    }
+}
+
+/*
+ * Finds the price of a model, the configured prices first.
+ * Dated snapshots like `gpt-4o-2024-08-06` use the price of their model, other suffixes
+ * such as `o1-pro` are different models with prices of their own.
+ */
+pub(crate) fn price_for(
+    model: &str,
+    overrides: &HashMap<String, ModelPrice>,
+) -> Option<ModelPrice> {
+    let find = |model: &str| {
+        overrides
+            .iter()
+            .map(|(name, price)| (name.as_str(), *price))
+            .chain(DEFAULT_PRICES)
+            .find(|(name, _)| *name == model)
+            .map(|(_, price)| price)
+    };
+    find(model).or_else(|| without_date_suffix(model).and_then(find))
+}
+
+// the model name of a `{model}-YYYY-MM-DD` snapshot
+fn without_date_suffix(model: &str) -> Option<&str> {
+    let (name, suffix) = model.split_at_checked(model.len().checked_sub(11)?)?;
+    let date = suffix.strip_prefix('-')?;
+    date.char_indices()
+        .all(|(index, c)| match index {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+        .then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_price_from_str() {
+        assert_eq!(
+            "2.5/10/1.25".parse::<ModelPrice>().unwrap(),
+            ModelPrice::new(2.5, 10.0, 1.25)
+        );
+        assert_eq!(
+            "0.5 / 1.5".parse::<ModelPrice>().unwrap(),
+            ModelPrice::new(0.5, 1.5, 0.5)
+        );
+        assert_eq!(
+            "free".parse::<ModelPrice>().unwrap_err().to_string(),
+            "Invalid model price: 'free'"
+        );
+        assert!("1/2/3/4".parse::<ModelPrice>().is_err());
+    }
+
+    #[test]
+    fn test_model_price_cost() {
+        let price = ModelPrice::new(2.0, 8.0, 0.5);
+        // 1000 uncached input, 1000 cached input and 500 output tokens
+        let cost = price.cost(2000, 1000, 500);
+        assert!((cost - (2000.0 + 500.0 + 4000.0) / 1_000_000.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_model_price_usage_cost() {
+        let usage: Usage = serde_json::from_str(
+            r#"{"prompt_tokens": 1000000, "completion_tokens": 1000000, "total_tokens": 2000000}"#,
+        )
+        .unwrap();
+        let cost = ModelPrice::new(0.15, 0.60, 0.075).usage_cost(&usage);
+        assert!((cost - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_price_for_builtin_models() {
+        let overrides = HashMap::new();
+        assert_eq!(
+            price_for("gpt-4o", &overrides),
+            Some(ModelPrice::new(2.50, 10.00, 1.25))
+        );
+        assert_eq!(
+            price_for("gpt-4o-mini-2024-07-18", &overrides),
+            Some(ModelPrice::new(0.15, 0.60, 0.075))
+        );
+        assert_eq!(
+            price_for("gpt-4o-2024-08-06", &overrides),
+            Some(ModelPrice::new(2.50, 10.00, 1.25))
+        );
+        assert_eq!(price_for("llama-3-70b", &overrides), None);
+        assert_eq!(price_for("gpt-4omni", &overrides), None);
+        // only dated snapshots share the price of their model
+        assert_eq!(price_for("o1-pro", &overrides), None);
+        assert_eq!(price_for("o1-mini", &overrides), None);
+        assert_eq!(price_for("gpt-4o-audio-preview", &overrides), None);
+        assert_eq!(price_for("gpt-4o-2024-08", &overrides), None);
+        assert_eq!(
+            price_for("o1-2024-12-17", &overrides),
+            Some(ModelPrice::new(15.00, 60.00, 7.50))
+        );
+    }
+
+    #[test]
+    fn test_price_for_overrides() {
+        let overrides = HashMap::from([
+            ("gpt-4o".to_string(), ModelPrice::new(1.0, 2.0, 0.5)),
+            ("llama-3-70b".to_string(), ModelPrice::new(0.5, 0.5, 0.5)),
+        ]);
+        assert_eq!(
+            price_for("gpt-4o", &overrides),
+            Some(ModelPrice::new(1.0, 2.0, 0.5))
+        );
+        assert_eq!(
+            price_for("llama-3-70b", &overrides),
+            Some(ModelPrice::new(0.5, 0.5, 0.5))
+        );
+        assert_eq!(
+            price_for("gpt-4o-2024-08-06", &overrides),
+            Some(ModelPrice::new(1.0, 2.0, 0.5))
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::openai_payload::Message;
+
+static EMAIL_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}").unwrap()
+});
+// digits are matched as `[0-9]`, since `\d` also matches non-ASCII digits
+static IBAN_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b[A-Z]{2}[0-9]{2}(?: ?[A-Z0-9]){11,30}\b").unwrap());
+static CREDIT_CARD_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b[0-9](?:[ -]?[0-9]){12,18}\b").unwrap());
+static IP_ADDRESS_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(?:[0-9]{1,3}\.){3}[0-9]{1,3}\b|[0-9A-Fa-f]{0,4}(?::[0-9A-Fa-f]{0,4}){2,7}")
+        .unwrap()
+});
+// an international prefix, an area code in parentheses, or at least three groups of digits
+static PHONE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(concat!(
+        r"\+[0-9][0-9 ().-]{6,}[0-9]",
+        r"|\([0-9]{2,4}\)[ .-]?[0-9][0-9 .-]{4,}[0-9]",
+        r"|\b[0-9]{2,4}(?:[ .-][0-9]{2,4}){2,5}\b",
+    ))
+    .unwrap()
+});
+static DATE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(?:[0-9]{4}[.-][0-9]{1,2}[.-][0-9]{1,2}|[0-9]{1,2}[.-][0-9]{1,2}[.-][0-9]{4})$")
+        .unwrap()
+});
+
+/*
+ * Kinds of personal data that can be redacted from conversation messages.
+ * Detectors run in this order, so that e.g. card numbers are not mistaken for phone numbers.
+ */
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiKind {
+    Email,
+    Iban,
+    CreditCard,
+    IpAddress,
+    Phone,
+}
+
+impl FromStr for PiiKind {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "email" => Ok(Self::Email),
+            "iban" => Ok(Self::Iban),
+            "credit_card" => Ok(Self::CreditCard),
+            "ip_address" => Ok(Self::IpAddress),
+            "phone" => Ok(Self::Phone),
+            _ => Err(anyhow::anyhow!("Invalid PII kind: '{value}'")),
+        }
+    }
+}
+
+impl PiiKind {
+    const ALL: [PiiKind; 5] = [
+        PiiKind::Email,
+        PiiKind::Iban,
+        PiiKind::CreditCard,
+        PiiKind::IpAddress,
+        PiiKind::Phone,
+    ];
+
+    fn placeholder_label(&self) -> &'static str {
+        match self {
+            PiiKind::Email => "EMAIL",
+            PiiKind::Iban => "IBAN",
+            PiiKind::CreditCard => "CREDIT_CARD",
+            PiiKind::IpAddress => "IP_ADDRESS",
+            PiiKind::Phone => "PHONE",
+        }
+    }
+
+    fn regex(&self) -> &'static Regex {
+        match self {
+            PiiKind::Email => &EMAIL_REGEX,
+            PiiKind::Iban => &IBAN_REGEX,
+            PiiKind::CreditCard => &CREDIT_CARD_REGEX,
+            PiiKind::IpAddress => &IP_ADDRESS_REGEX,
+            PiiKind::Phone => &PHONE_REGEX,
+        }
+    }
+
+    // filters out regex matches that are not valid values of this kind
+    fn is_valid(&self, candidate: &str) -> bool {
+        match self {
+            PiiKind::Email => true,
+            PiiKind::Iban => is_valid_iban(candidate),
+            PiiKind::CreditCard => is_valid_luhn(candidate),
+            PiiKind::IpAddress => {
+                candidate.parse::<Ipv4Addr>().is_ok() || candidate.parse::<Ipv6Addr>().is_ok()
+            }
+            PiiKind::Phone => {
+                let digits = candidate.chars().filter(char::is_ascii_digit).count();
+                (8..=15).contains(&digits) && !DATE_REGEX.is_match(candidate)
+            }
+        }
+    }
+
+    // returns the valid matches found in the text
+    pub(crate) fn detect<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.regex()
+            .find_iter(text)
+            .map(|m| m.as_str())
+            .filter(|candidate| self.is_valid(candidate))
+            .collect()
+    }
+}
+
+fn is_valid_luhn(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=19).contains(&digits.len()) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(index, &digit)| match index % 2 {
+            0 => digit,
+            _ if digit * 2 > 9 => digit * 2 - 9,
+            _ => digit * 2,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn is_valid_iban(candidate: &str) -> bool {
+    let iban: String = candidate.chars().filter(|c| !c.is_whitespace()).collect();
+    // the slicing below is by byte
+    if !iban.is_ascii() || !(15..=34).contains(&iban.len()) {
+        return false;
+    }
+    // move the country code and check digits to the end, then compute the remainder mod 97
+    let rearranged = iban[4..].chars().chain(iban[..4].chars());
+    let mut remainder: u32 = 0;
+    for c in rearranged {
+        let Some(value) = c.to_digit(36) else {
+            return false;
+        };
+        remainder = match value {
+            0..=9 => (remainder * 10 + value) % 97,
+            _ => (remainder * 100 + value) % 97,
+        };
+    }
+    remainder == 1
+}
+
+/*
+ * Replaces personal data by typed placeholders like `[EMAIL_1]`,
+ * and remembers the original values so they can be restored in the reply.
+ */
+pub(crate) struct Redactor {
+    kinds: Vec<PiiKind>,
+    replacements: Vec<(String, String)>,
+}
+
+impl Redactor {
+    pub(crate) fn new(kinds: &[PiiKind]) -> Self {
+        Redactor {
+            // keep the detection order stable, whatever the settings order
+            kinds: PiiKind::ALL
+                .into_iter()
+                .filter(|kind| kinds.contains(kind))
+                .collect(),
+            replacements: Vec::new(),
+        }
+    }
+
+    pub(crate) fn redact(&mut self, text: &str) -> String {
+        let mut text = text.to_string();
+        for kind in self.kinds.clone() {
+            let detected: Vec<String> = kind.detect(&text).into_iter().map(String::from).collect();
+            for original in detected {
+                let placeholder = self.placeholder_for(kind, &original);
+                text = text.replacen(&original, &placeholder, 1);
+            }
+        }
+        text
+    }
+
+    /*
+     * Every message the client sent is redacted, whatever its role: earlier assistant replies
+     * carry the restored values, and system or tool messages may hold personal data too.
+     * It runs before the configured system prompt is added, which is left as is.
+     */
+    pub(crate) fn redact_messages(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        if self.kinds.is_empty() {
+            return messages;
+        }
+        messages
+            .into_iter()
+            .map(|message| Message {
+                content: self.redact(&message.content),
+                ..message
+            })
+            .collect()
+    }
+
+    pub(crate) fn restore(&self, text: &str) -> String {
+        self.replacements
+            .iter()
+            .fold(text.to_string(), |text, (placeholder, original)| {
+                text.replace(placeholder, original)
+            })
+    }
+
+    // the same value always gets the same placeholder
+    fn placeholder_for(&mut self, kind: PiiKind, original: &str) -> String {
+        if let Some((placeholder, _)) = self.replacements.iter().find(|(_, o)| o == original) {
+            return placeholder.clone();
+        }
+        let label = kind.placeholder_label();
+        let count = self
+            .replacements
+            .iter()
+            .filter(|(placeholder, _)| placeholder.starts_with(&format!("[{label}_")))
+            .count();
+        let placeholder = format!("[{label}_{}]", count + 1);
+        self.replacements
+            .push((placeholder.clone(), original.to_string()));
+        placeholder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai_payload::Role;
+
+    #[test]
+    fn test_pii_kind_from_str() {
+        assert_eq!("email".parse::<PiiKind>().unwrap(), PiiKind::Email);
+        assert_eq!("iban".parse::<PiiKind>().unwrap(), PiiKind::Iban);
+        assert_eq!(
+            "credit_card".parse::<PiiKind>().unwrap(),
+            PiiKind::CreditCard
+        );
+        assert_eq!("ip_address".parse::<PiiKind>().unwrap(), PiiKind::IpAddress);
+        assert_eq!("phone".parse::<PiiKind>().unwrap(), PiiKind::Phone);
+        assert_eq!(
+            "ssn".parse::<PiiKind>().unwrap_err().to_string(),
+            "Invalid PII kind: 'ssn'"
+        );
+    }
+
+    #[test]
+    fn test_detect_email() {
+        let text = "Write to jane.doe+news@example.co.uk or bob@test.io, not to @someone.";
+        assert_eq!(
+            PiiKind::Email.detect(text),
+            vec!["jane.doe+news@example.co.uk", "bob@test.io"]
+        );
+    }
+
+    #[test]
+    fn test_detect_iban() {
+        let text = "Pay to FR76 3000 6000 0112 3456 7890 189 or GB82WEST12345698765432.";
+        assert_eq!(
+            PiiKind::Iban.detect(text),
+            vec![
+                "FR76 3000 6000 0112 3456 7890 189",
+                "GB82WEST12345698765432"
+            ]
+        );
+        // wrong check digits
+        assert!(PiiKind::Iban.detect("GB00WEST12345698765432").is_empty());
+    }
+
+    #[test]
+    fn test_detect_credit_card() {
+        let text = "My card is 4111 1111 1111 1111 and 5500-0000-0000-0004.";
+        assert_eq!(
+            PiiKind::CreditCard.detect(text),
+            vec!["4111 1111 1111 1111", "5500-0000-0000-0004"]
+        );
+        // fails the Luhn check
+        assert!(PiiKind::CreditCard.detect("4111 1111 1111 1112").is_empty());
+    }
+
+    #[test]
+    fn test_detect_ip_address() {
+        let text = "Servers 192.168.1.10, 2001:db8::8a2e:370:7334 but not 999.1.1.1 or 12:30.";
+        assert_eq!(
+            PiiKind::IpAddress.detect(text),
+            vec!["192.168.1.10", "2001:db8::8a2e:370:7334"]
+        );
+    }
+
+    #[test]
+    fn test_detect_phone() {
+        let text = "Call +33 6 12 34 56 78 or (555) 123-4567, order 1234 costs 99.";
+        assert_eq!(
+            PiiKind::Phone.detect(text),
+            vec!["+33 6 12 34 56 78", "(555) 123-4567"]
+        );
+        assert_eq!(
+            PiiKind::Phone.detect("Or 06 12 34 56 78, 06.12.34.56.78 and 555-123-4567."),
+            vec!["06 12 34 56 78", "06.12.34.56.78", "555-123-4567"]
+        );
+    }
+
+    #[test]
+    fn test_detect_phone_ignores_dates_and_numbers() {
+        for text in [
+            "Delivered on 2024-01-15",
+            "Delivered on 15.01.2024",
+            "Order 12345678 is late",
+            "Order #20240115, invoice 1234 5678",
+            "Total 1234.56 EUR",
+        ] {
+            assert!(PiiKind::Phone.detect(text).is_empty(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_is_valid_luhn() {
+        assert!(is_valid_luhn("4111111111111111"));
+        assert!(!is_valid_luhn("4111111111111112"));
+        assert!(!is_valid_luhn("4111"));
+    }
+
+    #[test]
+    fn test_is_valid_iban() {
+        assert!(is_valid_iban("DE89370400440532013000"));
+        assert!(!is_valid_iban("DE88370400440532013000"));
+        assert!(!is_valid_iban("DE89"));
+        assert!(!is_valid_iban("FR1٦30006000011234567890189"));
+    }
+
+    #[test]
+    fn test_detect_non_ascii_digits() {
+        let text = "FR1٦ 3000 6000 0112 3456 7890 189, 4111 1111 1111 111٦, 192.168.1.١٠";
+        for kind in PiiKind::ALL {
+            assert!(kind
+                .detect(text)
+                .iter()
+                .all(|candidate| candidate.is_ascii()));
+        }
+        let mut redactor = Redactor::new(&PiiKind::ALL);
+        assert!(redactor.redact(text).contains("FR1٦"));
+    }
+
+    #[test]
+    fn test_redactor_only_enabled_kinds() {
+        let mut redactor = Redactor::new(&[PiiKind::Email]);
+        let redacted = redactor.redact("Mail jane@example.com from 10.0.0.1");
+        assert_eq!(redacted, "Mail [EMAIL_1] from 10.0.0.1");
+    }
+
+    #[test]
+    fn test_redactor_typed_placeholders() {
+        let mut redactor = Redactor::new(&PiiKind::ALL);
+        let redacted = redactor.redact(
+            "jane@example.com paid with 4111 1111 1111 1111, cc bob@example.com and jane@example.com",
+        );
+        assert_eq!(
+            redacted,
+            "[EMAIL_1] paid with [CREDIT_CARD_1], cc [EMAIL_2] and [EMAIL_1]"
+        );
+    }
+
+    #[test]
+    fn test_redactor_restore() {
+        let mut redactor = Redactor::new(&PiiKind::ALL);
+        redactor.redact("I'm jane@example.com, call me at +33 6 12 34 56 78");
+        assert_eq!(
+            redactor.restore("Sure, I'll write to [EMAIL_1] and call [PHONE_1]."),
+            "Sure, I'll write to jane@example.com and call +33 6 12 34 56 78."
+        );
+    }
+
+    #[test]
+    fn test_redactor_redact_messages() {
+        let mut redactor = Redactor::new(&[PiiKind::Email]);
+        let message = |role, content: &str| Message {
+            role,
+            content: content.to_string(),
+        };
+        let messages = redactor.redact_messages(vec![
+            message(Role::System, "Support is at help@example.com"),
+            message(Role::User, "I'm jane@example.com"),
+            message(Role::Assistant, "Noted, jane@example.com."),
+            message(Role::Developer, "CC bob@example.com"),
+            message(Role::Tool, "{\"email\": \"bob@example.com\"}"),
+        ]);
+        assert_eq!(messages[0].content, "Support is at [EMAIL_1]");
+        assert_eq!(messages[1].content, "I'm [EMAIL_2]");
+        assert_eq!(messages[2].content, "Noted, [EMAIL_2].");
+        assert_eq!(messages[3].content, "CC [EMAIL_3]");
+        assert_eq!(messages[4].content, "{\"email\": \"[EMAIL_3]\"}");
+    }
+}
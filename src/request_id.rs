@@ -0,0 +1,54 @@
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+pub(crate) const UPSTREAM_REQUEST_ID_HEADER: &str = "x-upstream-request-id";
+// the header OpenAI records client-supplied ids from
+pub(crate) const CLIENT_REQUEST_ID_HEADER: &str = "x-client-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// the client's request id if usable, a new one otherwise
+pub(crate) fn request_id_from_headers(headers: &http::HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| is_valid_request_id(id))
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+// request ids are forwarded upstream and written to the logs, so only printable ids are accepted
+pub(crate) fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id.chars().all(|c| c.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_request_id_from_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("abc-123"));
+        assert_eq!(request_id_from_headers(&headers), "abc-123");
+    }
+
+    #[test]
+    fn test_request_id_from_headers_generated() {
+        let id = request_id_from_headers(&http::HeaderMap::new());
+        assert!(uuid::Uuid::parse_str(&id).is_ok());
+
+        let mut headers = http::HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, HeaderValue::from_static("not valid"));
+        assert_ne!(request_id_from_headers(&headers), "not valid");
+    }
+
+    #[test]
+    fn test_is_valid_request_id() {
+        assert!(is_valid_request_id("req_8f2c-11ef"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("two words"));
+        assert!(!is_valid_request_id(&"a".repeat(129)));
+    }
+}
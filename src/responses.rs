@@ -0,0 +1,384 @@
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::HttpError;
+use crate::openai_payload::{
+    generate_endpoint, CompletionTokensDetails, Message, OpenAIChoice, OpenAIResponse,
+    PromptTokensDetails, ReasoningEffort, Role, Usage, Verbosity,
+};
+
+const ENDPOINT: &str = "/v1/responses";
+const MAX_RESPONSE_ID_LENGTH: usize = 128;
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct InputItem {
+    role: Role,
+    content: String,
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct ReasoningOptions {
+    effort: ReasoningEffort,
+}
+
+#[derive(serde::Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct TextOptions {
+    verbosity: Verbosity,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct ResponsesPayload {
+    model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instructions: Option<String>,
+    input: Vec<InputItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_response_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reasoning: Option<ReasoningOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<TextOptions>,
+}
+
+impl ResponsesPayload {
+    /*
+     * System messages become the `instructions`, the other messages the `input` items.
+     * Instructions aren't carried over by `previous_response_id`, so they're always sent.
+     */
+    pub(crate) fn new(
+        model: String,
+        messages: &[Message],
+        max_output_tokens: Option<u32>,
+        previous_response_id: Option<String>,
+    ) -> Result<Self, HttpError> {
+        let mut instructions = Vec::new();
+        let mut input = Vec::new();
+        for (index, message) in messages.iter().enumerate() {
+            match message.role {
+                Role::System => instructions.push(message.content.as_str()),
+                Role::Tool => {
+                    return Err(HttpError::bad_request(format!(
+                        "Role 'tool' is not supported by the responses API in message {index}"
+                    )))
+                }
+                role => input.push(InputItem {
+                    role,
+                    content: message.content.clone(),
+                }),
+            }
+        }
+        Ok(ResponsesPayload {
+            model,
+            instructions: Some(instructions.join("\n\n")).filter(|i| !i.is_empty()),
+            input,
+            max_output_tokens,
+            previous_response_id,
+            temperature: None,
+            reasoning: None,
+            text: None,
+        })
+    }
+
+    pub(crate) fn with_temperature(mut self, temperature: Option<f32>) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    // like chat payloads, without the temperature, the settings going in `reasoning` and `text`
+    pub(crate) fn for_reasoning_model(
+        mut self,
+        reasoning_effort: Option<ReasoningEffort>,
+        verbosity: Option<Verbosity>,
+    ) -> Self {
+        self.temperature = None;
+        self.reasoning = reasoning_effort.map(|effort| ReasoningOptions { effort });
+        self.text = verbosity.map(|verbosity| TextOptions { verbosity });
+        self
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        hostname: Option<String>,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(self)?)),
+        )
+    }
+}
+
+// the response to continue from, the upstream keeping the earlier turns
+pub(crate) fn previous_response_id_from_body(
+    body: &serde_json::Value,
+) -> Result<Option<String>, HttpError> {
+    match body.get("previous_response_id") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(id))
+            if !id.is_empty()
+                && id.len() <= MAX_RESPONSE_ID_LENGTH
+                && id.chars().all(|c| c.is_ascii_graphic()) =>
+        {
+            Ok(Some(id.clone()))
+        }
+        Some(id) => Err(HttpError::bad_request(format!(
+            "Invalid previous_response_id {id}"
+        ))),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct OutputContent {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(serde::Deserialize)]
+struct OutputItem {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    content: Vec<OutputContent>,
+}
+
+#[derive(serde::Deserialize)]
+struct IncompleteDetails {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ResponsesUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+    #[serde(default)]
+    input_tokens_details: Option<PromptTokensDetails>,
+    #[serde(default)]
+    output_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ResponsesResponse {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    status: Option<String>,
+    #[serde(default)]
+    incomplete_details: Option<IncompleteDetails>,
+    output: Vec<OutputItem>,
+    #[serde(default)]
+    usage: Option<ResponsesUsage>,
+}
+
+impl ResponsesResponse {
+    /*
+     * Maps the `output` items to a single assistant choice, like a chat reply.
+     * Only the text of message items is kept: reasoning and tool call items are dropped.
+     */
+    pub(crate) fn from_json_string(response_body: &str) -> Result<OpenAIResponse, anyhow::Error> {
+        let response: ResponsesResponse = serde_json::from_str(response_body)?;
+        let content = response
+            .output
+            .iter()
+            .filter(|item| item.kind == "message")
+            .flat_map(|item| &item.content)
+            .filter(|content| content.kind == "output_text")
+            .map(|content| content.text.as_str())
+            .collect::<String>();
+        let finish_reason = match (response.status.as_deref(), response.incomplete_details) {
+            (Some("incomplete"), Some(details)) => match details.reason.as_deref() {
+                Some("max_output_tokens") => Some("length".to_string()),
+                reason => reason.map(String::from),
+            },
+            (Some("completed"), _) => Some("stop".to_string()),
+            (status, _) => status.map(String::from),
+        };
+        Ok(OpenAIResponse {
+            id: response.id,
+            choices: vec![OpenAIChoice {
+                message: Message {
+                    role: Role::Assistant,
+                    content,
+                },
+                finish_reason,
+            }],
+            usage: response.usage.map(|usage| Usage {
+                prompt_tokens: usage.input_tokens,
+                completion_tokens: usage.output_tokens,
+                prompt_tokens_details: usage.input_tokens_details,
+                completion_tokens_details: usage.output_tokens_details,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_responses_payload_serialization() {
+        let payload = ResponsesPayload::new(
+            "gpt-4.1".to_string(),
+            &[
+                message(Role::System, "Be brief."),
+                message(Role::Developer, "Answer in French."),
+                message(Role::User, "Hi"),
+            ],
+            Some(100),
+            Some("resp_123".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "model": "gpt-4.1",
+                "instructions": "Be brief.",
+                "input": [
+                    {"role": "developer", "content": "Answer in French."},
+                    {"role": "user", "content": "Hi"}
+                ],
+                "max_output_tokens": 100,
+                "previous_response_id": "resp_123"
+            })
+        );
+    }
+
+    #[test]
+    fn test_responses_payload_without_instructions() {
+        let payload = ResponsesPayload::new(
+            "gpt-4.1".to_string(),
+            &[message(Role::User, "Hi")],
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({"model": "gpt-4.1", "input": [{"role": "user", "content": "Hi"}]})
+        );
+
+        let err = ResponsesPayload::new(
+            "gpt-4.1".to_string(),
+            &[message(Role::User, "Hi"), message(Role::Tool, "42")],
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(err.status_code, http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_responses_payload_sampling_and_reasoning() {
+        let payload = || {
+            ResponsesPayload::new("o3".to_string(), &[message(Role::User, "Hi")], None, None)
+                .unwrap()
+                .with_temperature(Some(0.5))
+        };
+        assert_eq!(serde_json::to_value(payload()).unwrap()["temperature"], 0.5);
+
+        let payload =
+            payload().for_reasoning_model(Some(ReasoningEffort::High), Some(Verbosity::Low));
+        let payload = serde_json::to_value(&payload).unwrap();
+        assert!(payload.get("temperature").is_none());
+        assert_eq!(payload["reasoning"], json!({"effort": "high"}));
+        assert_eq!(payload["text"], json!({"verbosity": "low"}));
+    }
+
+    #[test]
+    fn test_previous_response_id_from_body() {
+        assert_eq!(
+            previous_response_id_from_body(&json!({"previous_response_id": "resp_1"})).unwrap(),
+            Some("resp_1".to_string())
+        );
+        assert_eq!(previous_response_id_from_body(&json!({})).unwrap(), None);
+        for id in [json!(""), json!(1), json!("resp 1")] {
+            assert!(
+                previous_response_id_from_body(&json!({ "previous_response_id": id })).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_responses_response_from_json_string() {
+        let body = json!({
+            "id": "resp_abc",
+            "status": "completed",
+            "output": [
+                {"type": "reasoning", "summary": []},
+                {"type": "message", "role": "assistant", "content": [
+                    {"type": "output_text", "text": "Hello", "annotations": []},
+                    {"type": "output_text", "text": " there!", "annotations": []}
+                ]}
+            ],
+            "usage": {"input_tokens": 12, "output_tokens": 4, "total_tokens": 16,
+                      "input_tokens_details": {"cached_tokens": 2},
+                      "output_tokens_details": {"reasoning_tokens": 1}}
+        });
+        let response = ResponsesResponse::from_json_string(&body.to_string()).unwrap();
+        assert_eq!(response.id.as_deref(), Some("resp_abc"));
+        assert_eq!(response.choices[0].message.role, Role::Assistant);
+        assert_eq!(response.choices[0].message.content, "Hello there!");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("stop"));
+        let usage = response.usage.unwrap();
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 4);
+        assert_eq!(usage.cached_tokens(), 2);
+        assert_eq!(usage.reasoning_tokens(), 1);
+    }
+
+    #[test]
+    fn test_responses_response_incomplete() {
+        let body = json!({
+            "status": "incomplete",
+            "incomplete_details": {"reason": "max_output_tokens"},
+            "output": []
+        });
+        let response = ResponsesResponse::from_json_string(&body.to_string()).unwrap();
+        assert_eq!(response.choices[0].message.content, "");
+        assert_eq!(response.choices[0].finish_reason.as_deref(), Some("length"));
+    }
+
+    #[test]
+    fn test_responses_generate_endpoint() {
+        let payload = ResponsesPayload::new(
+            "gpt-4.1".to_string(),
+            &[message(Role::User, "Hi")],
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            payload.generate_endpoint(Some("custom.example.com".to_string())),
+            "https://custom.example.com/v1/responses"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+/*
+ * Endpoints served by the component, relative to its `edgee_path`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Route {
+    Chat,
+    ChatStream,
+    Models,
+    Embeddings,
+    Transcription,
+    Speech,
+    Images,
+    BatchCreate,
+    BatchStatus,
+    BatchResults,
+    Health,
+    // a known path called with another method
+    MethodNotAllowed,
+    NotFound,
+}
+
+const ROUTES: [(&str, http::Method, Route); 12] = [
+    ("", http::Method::POST, Route::Chat),
+    ("/chat", http::Method::POST, Route::Chat),
+    ("/chat/stream", http::Method::POST, Route::ChatStream),
+    ("/models", http::Method::GET, Route::Models),
+    ("/embeddings", http::Method::POST, Route::Embeddings),
+    (
+        "/audio/transcriptions",
+        http::Method::POST,
+        Route::Transcription,
+    ),
+    ("/audio/speech", http::Method::POST, Route::Speech),
+    ("/images/generations", http::Method::POST, Route::Images),
+    ("/batches", http::Method::POST, Route::BatchCreate),
+    ("/batches/status", http::Method::GET, Route::BatchStatus),
+    ("/batches/results", http::Method::GET, Route::BatchResults),
+    ("/health", http::Method::GET, Route::Health),
+];
+
+impl Route {
+    /*
+     * Without a known `edgee_path`, sub-paths are matched at the end of the path
+     * and any other path is a chat request, like before routing existed.
+     */
+    pub(crate) fn resolve(method: &http::Method, path: &str, edgee_path: Option<&str>) -> Self {
+        let path = path.trim_end_matches('/');
+        let matched = match edgee_path.map(|base| base.trim_end_matches('/')) {
+            Some(base) => path.strip_prefix(base).and_then(|sub_path| {
+                ROUTES
+                    .iter()
+                    .find(|(route_path, _, _)| *route_path == sub_path)
+            }),
+            None => ROUTES
+                .iter()
+                .filter(|(route_path, _, _)| !route_path.is_empty())
+                .filter(|(route_path, _, _)| path.ends_with(route_path))
+                .max_by_key(|(route_path, _, _)| route_path.len())
+                .or(ROUTES.first()),
+        };
+        match matched {
+            Some((_, route_method, route)) if route_method == method => *route,
+            Some(_) => Route::MethodNotAllowed,
+            None => Route::NotFound,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Method;
+
+    const GET: Method = Method::GET;
+    const POST: Method = Method::POST;
+
+    #[test]
+    fn test_route_resolve_with_edgee_path() {
+        let base = Some("/ai/");
+        assert_eq!(Route::resolve(&POST, "/ai", base), Route::Chat);
+        assert_eq!(Route::resolve(&POST, "/ai/", base), Route::Chat);
+        assert_eq!(Route::resolve(&POST, "/ai/chat", base), Route::Chat);
+        assert_eq!(
+            Route::resolve(&POST, "/ai/chat/stream", base),
+            Route::ChatStream
+        );
+        assert_eq!(Route::resolve(&GET, "/ai/models", base), Route::Models);
+        assert_eq!(
+            Route::resolve(&POST, "/ai/embeddings", base),
+            Route::Embeddings
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/ai/audio/transcriptions", base),
+            Route::Transcription
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/ai/audio/speech", base),
+            Route::Speech
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/ai/images/generations", base),
+            Route::Images
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/ai/batches", base),
+            Route::BatchCreate
+        );
+        assert_eq!(
+            Route::resolve(&GET, "/ai/batches/status", base),
+            Route::BatchStatus
+        );
+        assert_eq!(
+            Route::resolve(&GET, "/ai/batches/results", base),
+            Route::BatchResults
+        );
+        assert_eq!(Route::resolve(&GET, "/ai/health", base), Route::Health);
+        assert_eq!(Route::resolve(&GET, "/ai/unknown", base), Route::NotFound);
+        assert_eq!(
+            Route::resolve(&GET, "/ai/chat/unknown", base),
+            Route::NotFound
+        );
+        assert_eq!(Route::resolve(&GET, "/other/models", base), Route::NotFound);
+    }
+
+    #[test]
+    fn test_route_resolve_without_edgee_path() {
+        assert_eq!(Route::resolve(&POST, "/", None), Route::Chat);
+        assert_eq!(Route::resolve(&POST, "/chat", None), Route::Chat);
+        assert_eq!(
+            Route::resolve(&POST, "/chat/stream", None),
+            Route::ChatStream
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/chat/embeddings", None),
+            Route::Embeddings
+        );
+        assert_eq!(Route::resolve(&GET, "/chat/models/", None), Route::Models);
+        assert_eq!(Route::resolve(&POST, "/anything", None), Route::Chat);
+    }
+
+    #[test]
+    fn test_route_resolve_method() {
+        let base = Some("/ai");
+        assert_eq!(
+            Route::resolve(&GET, "/ai/chat", base),
+            Route::MethodNotAllowed
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/ai/models", base),
+            Route::MethodNotAllowed
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/ai/batches/status", base),
+            Route::MethodNotAllowed
+        );
+        assert_eq!(Route::resolve(&GET, "/ai/unknown", base), Route::NotFound);
+        assert_eq!(
+            Route::resolve(&GET, "/anything", None),
+            Route::MethodNotAllowed
+        );
+        assert_eq!(
+            Route::resolve(&POST, "/chat/models", None),
+            Route::MethodNotAllowed
+        );
+    }
+}
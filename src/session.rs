@@ -0,0 +1,309 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::helpers::HttpError;
+use crate::openai_payload::Message;
+
+pub(crate) const DEFAULT_SESSION_TTL_SECONDS: u64 = 3600;
+pub(crate) const DEFAULT_SESSION_MAX_MESSAGES: usize = 50;
+const MAX_CONVERSATION_ID_LENGTH: usize = 128;
+
+/*
+ * Conversation history stored server-side, so clients only send the new messages.
+ * It never contains the injected system prompt.
+ */
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+pub(crate) struct Session {
+    pub(crate) messages: Vec<Message>,
+    pub(crate) updated_at: u64,
+}
+
+impl Session {
+    pub(crate) fn is_expired(&self, ttl_seconds: u64, now: u64) -> bool {
+        now.saturating_sub(self.updated_at) > ttl_seconds
+    }
+
+    // append messages, keeping only the most recent `max_messages`
+    pub(crate) fn append(&mut self, messages: Vec<Message>, max_messages: usize, now: u64) {
+        self.messages.extend(messages);
+        let excess = self.messages.len().saturating_sub(max_messages);
+        self.messages.drain(..excess);
+        self.updated_at = now;
+    }
+}
+
+pub(crate) trait SessionStore {
+    fn load(&self, conversation_id: &str) -> anyhow::Result<Option<Session>>;
+    fn save(&self, conversation_id: &str, session: &Session) -> anyhow::Result<()>;
+
+    // returns the stored session, or a new one if missing or expired
+    fn load_active(
+        &self,
+        conversation_id: &str,
+        ttl_seconds: u64,
+        now: u64,
+    ) -> anyhow::Result<Session> {
+        Ok(self
+            .load(conversation_id)?
+            .filter(|session| !session.is_expired(ttl_seconds, now))
+            .unwrap_or_default())
+    }
+}
+
+/*
+ * Stores sessions as JSON documents in a key-value HTTP service:
+ * `GET {url}/{conversation_id}` to load (404 when missing) and `PUT {url}/{conversation_id}` to save.
+ */
+#[cfg(not(test))]
+pub(crate) struct HttpSessionStore {
+    url: String,
+}
+
+#[cfg(not(test))]
+impl SessionStore for HttpSessionStore {
+    fn load(&self, conversation_id: &str) -> anyhow::Result<Option<Session>> {
+        let response = waki::Client::new()
+            .get(&format!("{}/{conversation_id}", self.url))
+            .send()?;
+        match response.status_code() {
+            404 => Ok(None),
+            200..=299 => Ok(Some(serde_json::from_slice(&response.body()?)?)),
+            status => Err(anyhow::anyhow!(
+                "Could not load session: store responded with status {status}"
+            )),
+        }
+    }
+
+    fn save(&self, conversation_id: &str, session: &Session) -> anyhow::Result<()> {
+        let response = waki::Client::new()
+            .put(&format!("{}/{conversation_id}", self.url))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(session)?)
+            .send()?;
+        match response.status_code() {
+            200..=299 => Ok(()),
+            status => Err(anyhow::anyhow!(
+                "Could not save session: store responded with status {status}"
+            )),
+        }
+    }
+}
+
+/*
+ * A conversation loaded from the session store for the current request.
+ */
+pub(crate) struct Conversation {
+    pub(crate) id: String,
+    pub(crate) session: Session,
+    store: Box<dyn SessionStore>,
+}
+
+impl Conversation {
+    pub(crate) fn load(id: String, store_url: &str, ttl_seconds: u64) -> anyhow::Result<Self> {
+        let store = Self::store(store_url);
+        let session = store.load_active(&id, ttl_seconds, now())?;
+        Ok(Conversation { id, session, store })
+    }
+
+    #[cfg(not(test))]
+    fn store(store_url: &str) -> Box<dyn SessionStore> {
+        Box::new(HttpSessionStore {
+            url: store_url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    // the stored history followed by the new messages
+    pub(crate) fn history_with(&self, messages: &[Message]) -> Vec<Message> {
+        self.session
+            .messages
+            .iter()
+            .chain(messages)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn save(
+        mut self,
+        messages: Vec<Message>,
+        max_messages: usize,
+    ) -> anyhow::Result<()> {
+        self.session.append(messages, max_messages, now());
+        self.store.save(&self.id, &self.session)
+    }
+}
+
+// a new id is generated when the client starts a conversation
+pub(crate) fn conversation_id_from_body(body: &serde_json::Value) -> Result<String, HttpError> {
+    match body.get("conversation_id") {
+        None | Some(serde_json::Value::Null) => Ok(new_conversation_id()),
+        Some(serde_json::Value::String(id)) if is_valid_conversation_id(id) => Ok(id.clone()),
+        Some(id) => Err(HttpError::bad_request(format!(
+            "Invalid conversation_id {id}"
+        ))),
+    }
+}
+
+pub(crate) fn new_conversation_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+// conversation ids end up in store URLs, so only simple ids are accepted
+pub(crate) fn is_valid_conversation_id(conversation_id: &str) -> bool {
+    !conversation_id.is_empty()
+        && conversation_id.len() <= MAX_CONVERSATION_ID_LENGTH
+        && conversation_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openai_payload::Role;
+    use std::collections::HashMap;
+    use std::sync::{LazyLock, Mutex};
+
+    // Mock store to avoid real HTTP calls, keeping conversations in memory
+    static MEMORY_SESSIONS: LazyLock<Mutex<HashMap<String, Session>>> =
+        LazyLock::new(|| Mutex::new(HashMap::new()));
+
+    pub(crate) struct MemorySessionStore;
+
+    impl SessionStore for MemorySessionStore {
+        fn load(&self, conversation_id: &str) -> anyhow::Result<Option<Session>> {
+            let sessions = MEMORY_SESSIONS
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Session store is poisoned"))?;
+            Ok(sessions.get(conversation_id).cloned())
+        }
+
+        fn save(&self, conversation_id: &str, session: &Session) -> anyhow::Result<()> {
+            let mut sessions = MEMORY_SESSIONS
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Session store is poisoned"))?;
+            sessions.insert(conversation_id.to_string(), session.clone());
+            Ok(())
+        }
+    }
+
+    impl Conversation {
+        pub(crate) fn store(_store_url: &str) -> Box<dyn SessionStore> {
+            Box::new(MemorySessionStore)
+        }
+    }
+
+    const STORE_URL: &str = "https://sessions.example.com";
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_session_is_expired() {
+        let session = Session {
+            messages: vec![],
+            updated_at: 1000,
+        };
+        assert!(!session.is_expired(60, 1060));
+        assert!(session.is_expired(60, 1061));
+    }
+
+    #[test]
+    fn test_session_append_keeps_recent_messages() {
+        let mut session = Session::default();
+        session.append(
+            vec![
+                message(Role::User, "1"),
+                message(Role::Assistant, "2"),
+                message(Role::User, "3"),
+            ],
+            2,
+            42,
+        );
+        let contents: Vec<&str> = session
+            .messages
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["2", "3"]);
+        assert_eq!(session.updated_at, 42);
+    }
+
+    #[test]
+    fn test_memory_store_roundtrip() {
+        let store = MemorySessionStore;
+        let conversation_id = new_conversation_id();
+        assert!(store.load(&conversation_id).unwrap().is_none());
+
+        let mut session = Session::default();
+        session.append(vec![message(Role::User, "Hello")], 10, 100);
+        store.save(&conversation_id, &session).unwrap();
+
+        let loaded = store.load(&conversation_id).unwrap().unwrap();
+        assert_eq!(loaded.messages.len(), 1);
+        assert_eq!(loaded.updated_at, 100);
+    }
+
+    #[test]
+    fn test_load_active_drops_expired_session() {
+        let store = MemorySessionStore;
+        let conversation_id = new_conversation_id();
+        let mut session = Session::default();
+        session.append(vec![message(Role::User, "Hello")], 10, 100);
+        store.save(&conversation_id, &session).unwrap();
+
+        let active = store.load_active(&conversation_id, 60, 150).unwrap();
+        assert_eq!(active.messages.len(), 1);
+
+        let expired = store.load_active(&conversation_id, 60, 200).unwrap();
+        assert!(expired.messages.is_empty());
+    }
+
+    #[test]
+    fn test_conversation_history_and_save() {
+        let conversation_id = new_conversation_id();
+        let conversation = Conversation::load(conversation_id.clone(), STORE_URL, 60).unwrap();
+        assert!(conversation.session.messages.is_empty());
+        conversation
+            .save(
+                vec![message(Role::User, "Hi"), message(Role::Assistant, "Hello")],
+                10,
+            )
+            .unwrap();
+
+        let conversation = Conversation::load(conversation_id, STORE_URL, 60).unwrap();
+        let history = conversation.history_with(&[message(Role::User, "How are you?")]);
+        let contents: Vec<&str> = history.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["Hi", "Hello", "How are you?"]);
+    }
+
+    #[test]
+    fn test_conversation_id_from_body() {
+        let id = conversation_id_from_body(&serde_json::json!({"conversation_id": "abc"}));
+        assert_eq!(id.unwrap(), "abc");
+
+        let id = conversation_id_from_body(&serde_json::json!({}));
+        assert!(is_valid_conversation_id(&id.unwrap()));
+
+        let err = conversation_id_from_body(&serde_json::json!({"conversation_id": 42}));
+        assert_eq!(err.unwrap_err().to_string(), "Invalid conversation_id 42");
+    }
+
+    #[test]
+    fn test_is_valid_conversation_id() {
+        assert!(is_valid_conversation_id("abc-123_DEF"));
+        assert!(is_valid_conversation_id(&new_conversation_id()));
+        assert!(!is_valid_conversation_id(""));
+        assert!(!is_valid_conversation_id("../admin"));
+        assert!(!is_valid_conversation_id(&"a".repeat(129)));
+    }
+}
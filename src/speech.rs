@@ -0,0 +1,259 @@
+use std::str::FromStr;
+
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::HttpError;
+use crate::openai_payload::generate_endpoint;
+use crate::redaction::Redactor;
+
+pub(crate) const DEFAULT_SPEECH_MODEL: &str = "tts-1";
+pub(crate) const DEFAULT_SPEECH_VOICE: &str = "alloy";
+const ENDPOINT: &str = "/v1/audio/speech";
+// the upstream rejects longer inputs
+const MAX_INPUT_CHARS: usize = 4096;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeechFormat {
+    #[default]
+    Mp3,
+    Opus,
+    Aac,
+    Flac,
+    Wav,
+    Pcm,
+}
+
+impl SpeechFormat {
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            SpeechFormat::Mp3 => "audio/mpeg",
+            SpeechFormat::Opus => "audio/opus",
+            SpeechFormat::Aac => "audio/aac",
+            SpeechFormat::Flac => "audio/flac",
+            SpeechFormat::Wav => "audio/wav",
+            SpeechFormat::Pcm => "audio/pcm",
+        }
+    }
+}
+
+impl FromStr for SpeechFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "mp3" => Ok(Self::Mp3),
+            "opus" => Ok(Self::Opus),
+            "aac" => Ok(Self::Aac),
+            "flac" => Ok(Self::Flac),
+            "wav" => Ok(Self::Wav),
+            "pcm" => Ok(Self::Pcm),
+            _ => Err(anyhow::anyhow!("Invalid speech_format setting: '{value}'")),
+        }
+    }
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub(crate) struct SpeechPayload {
+    model: String,
+    input: String,
+    voice: String,
+    response_format: SpeechFormat,
+}
+
+impl SpeechPayload {
+    // validates the client's `{input, voice, format}`, the voice and format defaulting to the settings
+    pub(crate) fn from_request_body(
+        model: String,
+        body: &serde_json::Value,
+        default_voice: &str,
+        default_format: SpeechFormat,
+    ) -> Result<Self, HttpError> {
+        let input = match body.get("input") {
+            Some(serde_json::Value::String(input))
+                if !input.trim().is_empty() && input.chars().count() <= MAX_INPUT_CHARS =>
+            {
+                input.clone()
+            }
+            Some(_) => {
+                return Err(HttpError::bad_request(format!(
+                    "Invalid input, expected a string of 1 to {MAX_INPUT_CHARS} characters"
+                )))
+            }
+            None => {
+                return Err(HttpError::bad_request(
+                    "Missing 'input' field in request body",
+                ))
+            }
+        };
+        let voice = match body.get("voice") {
+            None | Some(serde_json::Value::Null) => default_voice.to_string(),
+            Some(serde_json::Value::String(voice))
+                if !voice.is_empty() && voice.chars().all(|c| c.is_ascii_alphanumeric()) =>
+            {
+                voice.clone()
+            }
+            Some(voice) => return Err(HttpError::bad_request(format!("Invalid voice {voice}"))),
+        };
+        let response_format = match body.get("format") {
+            None | Some(serde_json::Value::Null) => default_format,
+            Some(format) => format
+                .as_str()
+                .and_then(|format| format.parse().ok())
+                .ok_or_else(|| HttpError::bad_request(format!("Invalid format {format}")))?,
+        };
+        Ok(SpeechPayload {
+            model,
+            input,
+            voice,
+            response_format,
+        })
+    }
+
+    pub(crate) fn format(&self) -> SpeechFormat {
+        self.response_format
+    }
+
+    pub(crate) fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub(crate) fn redact(&mut self, redactor: &mut Redactor) {
+        self.input = redactor.redact(&self.input);
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        hostname: Option<String>,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some(("application/json", serde_json::to_vec(self)?)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Mock send method to avoid real HTTP call, answering with a few bytes of "audio",
+    // and rejecting the "upstream error" input
+    pub struct MockSpeechResponse {
+        status: u16,
+        headers: http::HeaderMap,
+    }
+    impl MockSpeechResponse {
+        pub fn status_code(&self) -> u16 {
+            self.status
+        }
+        pub fn headers(&self) -> &http::HeaderMap {
+            &self.headers
+        }
+        pub fn body(&self) -> anyhow::Result<Vec<u8>> {
+            match self.status {
+                200 => Ok(b"\xff\xfb\x90\x00".to_vec()),
+                _ => Ok(serde_json::json!({"error": {"message": "Rejected upstream", "type": "invalid_request_error"}}).to_string().into()),
+            }
+        }
+    }
+
+    impl SpeechPayload {
+        pub fn send(
+            &self,
+            _hostname: Option<String>,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockSpeechResponse> {
+            Ok(MockSpeechResponse {
+                status: match self.input.as_str() {
+                    "upstream error" => 400,
+                    _ => 200,
+                },
+                headers: http::HeaderMap::new(),
+            })
+        }
+    }
+
+    fn parse_payload(body: serde_json::Value) -> Result<SpeechPayload, HttpError> {
+        SpeechPayload::from_request_body(
+            DEFAULT_SPEECH_MODEL.to_string(),
+            &body,
+            DEFAULT_SPEECH_VOICE,
+            SpeechFormat::default(),
+        )
+    }
+
+    #[test]
+    fn test_speech_payload_serialization() {
+        let payload = parse_payload(json!({"input": "Hello"})).unwrap();
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({"model": "tts-1", "input": "Hello", "voice": "alloy", "response_format": "mp3"})
+        );
+
+        let payload =
+            parse_payload(json!({"input": "Hello", "voice": "nova", "format": "wav"})).unwrap();
+        assert_eq!(payload.format(), SpeechFormat::Wav);
+        assert_eq!(serde_json::to_value(&payload).unwrap()["voice"], "nova");
+    }
+
+    #[test]
+    fn test_speech_payload_redact() {
+        let mut payload = parse_payload(json!({"input": "Call jane@example.com"})).unwrap();
+        payload.redact(&mut Redactor::new(&[crate::redaction::PiiKind::Email]));
+        assert_eq!(payload.input(), "Call [EMAIL_1]");
+    }
+
+    #[test]
+    fn test_speech_payload_invalid() {
+        assert_eq!(
+            parse_payload(json!({})).unwrap_err().to_string(),
+            "Missing 'input' field in request body"
+        );
+        for body in [
+            json!({"input": " "}),
+            json!({"input": "a".repeat(MAX_INPUT_CHARS + 1)}),
+            json!({"input": "Hi", "voice": "../admin"}),
+            json!({"input": "Hi", "format": "ogg"}),
+        ] {
+            let err = parse_payload(body).unwrap_err();
+            assert_eq!(err.status_code, http::StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_speech_format() {
+        assert_eq!("opus".parse::<SpeechFormat>().unwrap(), SpeechFormat::Opus);
+        assert_eq!(SpeechFormat::Mp3.content_type(), "audio/mpeg");
+        assert_eq!(SpeechFormat::Wav.content_type(), "audio/wav");
+        assert_eq!(
+            "ogg".parse::<SpeechFormat>().unwrap_err().to_string(),
+            "Invalid speech_format setting: 'ogg'"
+        );
+    }
+
+    #[test]
+    fn test_speech_generate_endpoint() {
+        let payload = parse_payload(json!({"input": "Hello"})).unwrap();
+        assert_eq!(
+            payload.generate_endpoint(None),
+            "https://api.openai.com/v1/audio/speech"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+const DONE_EVENT: &str = "data: [DONE]\n\n";
+const DEFAULT_FINISH_REASON: &str = "stop";
+
+/*
+ * Renders a chat reply as the server-sent events of a streamed chat completion.
+ * The reply is complete when streaming starts: guardrails have already checked it.
+ * Every chunk carries the same `id` and `created` timestamp, like OpenAI's.
+ */
+pub(crate) fn chat_completion_events(
+    reply: &serde_json::Value,
+    id: &str,
+    created: u64,
+    model: &str,
+    finish_reason: Option<&str>,
+) -> String {
+    let chunk = |delta: serde_json::Value, finish_reason: Option<&str>| {
+        let mut chunk = serde_json::json!({
+            "id": id,
+            "object": "chat.completion.chunk",
+            "created": created,
+            "model": model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        });
+        if let Some(conversation_id) = reply.get("conversation_id") {
+            chunk["conversation_id"] = conversation_id.clone();
+        }
+        format!("data: {chunk}\n\n")
+    };
+
+    let delta = serde_json::json!({
+        "role": reply.get("role"),
+        "content": reply.get("content"),
+    });
+    [
+        chunk(delta, None),
+        chunk(
+            serde_json::json!({}),
+            Some(finish_reason.unwrap_or(DEFAULT_FINISH_REASON)),
+        ),
+        DONE_EVENT.to_string(),
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn events(stream: &str) -> Vec<&str> {
+        stream
+            .split("\n\n")
+            .filter(|event| !event.is_empty())
+            .map(|event| event.strip_prefix("data: ").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_chat_completion_events() {
+        let reply = json!({"role": "assistant", "content": "Hello!"});
+        let stream =
+            chat_completion_events(&reply, "chatcmpl-42", 1700000000, "gpt-4o", Some("length"));
+        let events = events(&stream);
+        assert_eq!(events.len(), 3);
+
+        let first: serde_json::Value = serde_json::from_str(events[0]).unwrap();
+        assert_eq!(first["id"], "chatcmpl-42");
+        assert_eq!(first["object"], "chat.completion.chunk");
+        assert_eq!(first["created"], 1700000000);
+        assert_eq!(first["model"], "gpt-4o");
+        assert_eq!(
+            first["choices"][0]["delta"],
+            json!({"role": "assistant", "content": "Hello!"})
+        );
+        assert!(first["choices"][0]["finish_reason"].is_null());
+
+        let last: serde_json::Value = serde_json::from_str(events[1]).unwrap();
+        assert_eq!(last["id"], first["id"]);
+        assert_eq!(last["created"], first["created"]);
+        assert_eq!(last["choices"][0]["delta"], json!({}));
+        assert_eq!(last["choices"][0]["finish_reason"], "length");
+
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    #[test]
+    fn test_chat_completion_events_conversation_id() {
+        let reply = json!({"role": "assistant", "content": "Hi", "conversation_id": "abc"});
+        let stream = chat_completion_events(&reply, "chatcmpl-42", 1700000000, "gpt-4o", None);
+        let first: serde_json::Value = serde_json::from_str(events(&stream)[0]).unwrap();
+        assert_eq!(first["conversation_id"], "abc");
+        let last: serde_json::Value = serde_json::from_str(events(&stream)[1]).unwrap();
+        assert_eq!(last["choices"][0]["finish_reason"], "stop");
+    }
+}
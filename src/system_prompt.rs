@@ -0,0 +1,184 @@
+use std::str::FromStr;
+
+use crate::openai_payload::{Message, Role};
+
+pub(crate) const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful assistant.";
+
+/*
+ * Decides how the configured system prompt interacts with the system messages
+ * sent by the client.
+ */
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemPromptPolicy {
+    // always prepend the configured prompt, keeping client system messages
+    #[default]
+    Prepend,
+    // drop client system messages and prepend the configured prompt
+    ReplaceClient,
+    // keep client system messages if any, otherwise prepend the configured prompt
+    ClientIfPresent,
+    // drop client system messages without injecting anything
+    StripClient,
+    // never inject a system prompt, forward client messages untouched
+    None,
+}
+
+impl FromStr for SystemPromptPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "prepend" => Ok(Self::Prepend),
+            "replace_client" => Ok(Self::ReplaceClient),
+            "client_if_present" => Ok(Self::ClientIfPresent),
+            "strip_client" => Ok(Self::StripClient),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow::anyhow!(
+                "Invalid system_prompt_policy setting: '{value}'"
+            )),
+        }
+    }
+}
+
+impl SystemPromptPolicy {
+    pub(crate) fn apply(&self, mut messages: Vec<Message>, system_prompt: &str) -> Vec<Message> {
+        let has_client_system = messages.iter().any(|m| m.role == Role::System);
+
+        let (strip_client, inject) = match self {
+            Self::Prepend => (false, true),
+            Self::ReplaceClient => (true, true),
+            Self::ClientIfPresent => (false, !has_client_system),
+            Self::StripClient => (true, false),
+            Self::None => (false, false),
+        };
+
+        if strip_client {
+            messages.retain(|m| m.role != Role::System);
+        }
+
+        if inject {
+            messages.insert(
+                0,
+                Message {
+                    role: Role::System,
+                    content: system_prompt.to_string(),
+                },
+            );
+        }
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: Role, content: &str) -> Message {
+        Message {
+            role,
+            content: content.to_string(),
+        }
+    }
+
+    fn client_messages() -> Vec<Message> {
+        vec![
+            message(Role::System, "client prompt"),
+            message(Role::User, "Hello"),
+        ]
+    }
+
+    fn roles_and_contents(messages: &[Message]) -> Vec<(&str, &str)> {
+        messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_policy_from_str() {
+        assert_eq!(
+            "prepend".parse::<SystemPromptPolicy>().unwrap(),
+            SystemPromptPolicy::Prepend
+        );
+        assert_eq!(
+            "replace_client".parse::<SystemPromptPolicy>().unwrap(),
+            SystemPromptPolicy::ReplaceClient
+        );
+        assert_eq!(
+            "client_if_present".parse::<SystemPromptPolicy>().unwrap(),
+            SystemPromptPolicy::ClientIfPresent
+        );
+        assert_eq!(
+            "strip_client".parse::<SystemPromptPolicy>().unwrap(),
+            SystemPromptPolicy::StripClient
+        );
+        assert_eq!(
+            "none".parse::<SystemPromptPolicy>().unwrap(),
+            SystemPromptPolicy::None
+        );
+    }
+
+    #[test]
+    fn test_policy_from_str_invalid() {
+        let result = "merge".parse::<SystemPromptPolicy>();
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid system_prompt_policy setting: 'merge'"
+        );
+    }
+
+    #[test]
+    fn test_apply_prepend() {
+        let messages = SystemPromptPolicy::Prepend.apply(client_messages(), "ours");
+        assert_eq!(
+            roles_and_contents(&messages),
+            vec![
+                ("system", "ours"),
+                ("system", "client prompt"),
+                ("user", "Hello")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_replace_client() {
+        let messages = SystemPromptPolicy::ReplaceClient.apply(client_messages(), "ours");
+        assert_eq!(
+            roles_and_contents(&messages),
+            vec![("system", "ours"), ("user", "Hello")]
+        );
+    }
+
+    #[test]
+    fn test_apply_client_if_present() {
+        let messages = SystemPromptPolicy::ClientIfPresent.apply(client_messages(), "ours");
+        assert_eq!(
+            roles_and_contents(&messages),
+            vec![("system", "client prompt"), ("user", "Hello")]
+        );
+
+        let messages =
+            SystemPromptPolicy::ClientIfPresent.apply(vec![message(Role::User, "Hello")], "ours");
+        assert_eq!(
+            roles_and_contents(&messages),
+            vec![("system", "ours"), ("user", "Hello")]
+        );
+    }
+
+    #[test]
+    fn test_apply_strip_client() {
+        let messages = SystemPromptPolicy::StripClient.apply(client_messages(), "ours");
+        assert_eq!(roles_and_contents(&messages), vec![("user", "Hello")]);
+    }
+
+    #[test]
+    fn test_apply_none() {
+        let messages = SystemPromptPolicy::None.apply(client_messages(), "ours");
+        assert_eq!(
+            roles_and_contents(&messages),
+            vec![("system", "client prompt"), ("user", "Hello")]
+        );
+    }
+}
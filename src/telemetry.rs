@@ -0,0 +1,538 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(test))]
+use waki::Response;
+
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+pub(crate) const DEFAULT_SERVICE_NAME: &str = "openai-chat-component";
+const TRACES_PATH: &str = "/v1/traces";
+const SAMPLED_FLAGS: &str = "01";
+
+/*
+ * W3C trace context, read from the client's `traceparent` header or started here.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TraceContext {
+    pub(crate) trace_id: String,
+    pub(crate) parent_span_id: Option<String>,
+    pub(crate) flags: String,
+}
+
+impl TraceContext {
+    // `00-{trace id}-{parent span id}-{flags}`, anything else starts a new trace
+    pub(crate) fn from_traceparent(traceparent: Option<&str>) -> Self {
+        traceparent
+            .and_then(Self::parse)
+            .unwrap_or_else(|| TraceContext {
+                trace_id: random_id(16),
+                parent_span_id: None,
+                flags: SAMPLED_FLAGS.to_string(),
+            })
+    }
+
+    fn parse(traceparent: &str) -> Option<Self> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        let [version, trace_id, parent_span_id, flags] = parts[..] else {
+            return None;
+        };
+        let is_hex = |value: &str, len: usize| {
+            value.len() == len
+                && value.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+                && value.chars().any(|c| c != '0')
+        };
+        if version != "00" || !is_hex(trace_id, 32) || !is_hex(parent_span_id, 16) {
+            return None;
+        }
+        if flags.len() != 2 || !flags.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id: trace_id.to_string(),
+            parent_span_id: Some(parent_span_id.to_string()),
+            flags: flags.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    StringArray(Vec<String>),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<u64> for AttributeValue {
+    fn from(value: u64) -> Self {
+        AttributeValue::Int(value as i64)
+    }
+}
+
+impl From<u32> for AttributeValue {
+    fn from(value: u32) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<u16> for AttributeValue {
+    fn from(value: u16) -> Self {
+        AttributeValue::Int(value.into())
+    }
+}
+
+impl From<f64> for AttributeValue {
+    fn from(value: f64) -> Self {
+        AttributeValue::Double(value)
+    }
+}
+
+impl From<Vec<String>> for AttributeValue {
+    fn from(value: Vec<String>) -> Self {
+        AttributeValue::StringArray(value)
+    }
+}
+
+impl AttributeValue {
+    fn to_otlp_json(&self) -> serde_json::Value {
+        match self {
+            AttributeValue::String(value) => serde_json::json!({ "stringValue": value }),
+            // 64-bit integers are strings in OTLP/JSON
+            AttributeValue::Int(value) => serde_json::json!({ "intValue": value.to_string() }),
+            AttributeValue::Double(value) => serde_json::json!({ "doubleValue": value }),
+            AttributeValue::StringArray(values) => serde_json::json!({
+                "arrayValue": {
+                    "values": values
+                        .iter()
+                        .map(|value| serde_json::json!({ "stringValue": value }))
+                        .collect::<Vec<_>>()
+                }
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Span {
+    pub(crate) name: String,
+    pub(crate) span_id: String,
+    pub(crate) parent_span_id: Option<String>,
+    pub(crate) kind: SpanKind,
+    pub(crate) start_time: u128,
+    pub(crate) end_time: u128,
+    pub(crate) attributes: Vec<(String, AttributeValue)>,
+    pub(crate) error: Option<String>,
+}
+
+impl Span {
+    fn new(name: &str, kind: SpanKind, parent_span_id: Option<String>) -> Self {
+        Span {
+            name: name.to_string(),
+            span_id: random_id(8),
+            parent_span_id,
+            kind,
+            start_time: now_nanos(),
+            end_time: 0,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    fn to_otlp_json(&self, trace_id: &str) -> serde_json::Value {
+        let mut span = serde_json::json!({
+            "traceId": trace_id,
+            "spanId": self.span_id,
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": self.start_time.to_string(),
+            "endTimeUnixNano": self.end_time.to_string(),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(key, value)| serde_json::json!({ "key": key, "value": value.to_otlp_json() }))
+                .collect::<Vec<_>>(),
+            "status": match &self.error {
+                Some(message) => serde_json::json!({ "code": 2, "message": message }),
+                None => serde_json::json!({ "code": 1 }),
+            },
+        });
+        if let Some(parent_span_id) = &self.parent_span_id {
+            span["parentSpanId"] = serde_json::json!(parent_span_id);
+        }
+        span
+    }
+}
+
+/*
+ * Records the spans of one request: a server span for the whole request,
+ * and one child span per phase, the current phase ending when the next one starts.
+ * Upstream calls made during a phase, like moderation, are recorded as client spans nested in it.
+ */
+pub(crate) struct Tracer {
+    pub(crate) context: TraceContext,
+    root: Span,
+    current: Option<Span>,
+    call: Option<Span>,
+    pub(crate) spans: Vec<Span>,
+}
+
+impl Tracer {
+    pub(crate) fn new(name: &str, traceparent: Option<&str>) -> Self {
+        let context = TraceContext::from_traceparent(traceparent);
+        let root = Span::new(name, SpanKind::Server, context.parent_span_id.clone());
+        Tracer {
+            context,
+            root,
+            current: None,
+            call: None,
+            spans: Vec::new(),
+        }
+    }
+
+    pub(crate) fn start_phase(&mut self, name: &str, kind: SpanKind) {
+        self.end_phase(None);
+        self.current = Some(Span::new(name, kind, Some(self.root.span_id.clone())));
+    }
+
+    fn end_phase(&mut self, error: Option<&str>) {
+        self.end_call(error);
+        if let Some(mut span) = self.current.take() {
+            span.end_time = now_nanos();
+            span.error = error.map(String::from);
+            self.spans.push(span);
+        }
+    }
+
+    // a client span for an upstream call, child of the current phase
+    pub(crate) fn start_call(&mut self, name: &str) {
+        self.end_call(None);
+        let parent_span_id = self.current.as_ref().unwrap_or(&self.root).span_id.clone();
+        self.call = Some(Span::new(name, SpanKind::Client, Some(parent_span_id)));
+    }
+
+    pub(crate) fn end_call(&mut self, error: Option<&str>) {
+        if let Some(mut span) = self.call.take() {
+            span.end_time = now_nanos();
+            span.error = error.map(String::from);
+            self.spans.push(span);
+        }
+    }
+
+    // sets an attribute on the current call or phase, or on the request span between phases
+    pub(crate) fn set_attribute(&mut self, key: &str, value: impl Into<AttributeValue>) {
+        let span = self
+            .call
+            .as_mut()
+            .or(self.current.as_mut())
+            .unwrap_or(&mut self.root);
+        span.attributes.push((key.to_string(), value.into()));
+    }
+
+    pub(crate) fn set_root_attribute(&mut self, key: &str, value: impl Into<AttributeValue>) {
+        self.root.attributes.push((key.to_string(), value.into()));
+    }
+
+    // the `traceparent` header to send upstream, child of the current span
+    pub(crate) fn traceparent(&self) -> String {
+        let span_id = self
+            .call
+            .as_ref()
+            .or(self.current.as_ref())
+            .unwrap_or(&self.root)
+            .span_id
+            .as_str();
+        format!(
+            "00-{}-{span_id}-{}",
+            self.context.trace_id, self.context.flags
+        )
+    }
+
+    // ends the request span, and the current phase with the same error if the request failed
+    pub(crate) fn finish(&mut self, error: Option<&str>) {
+        self.end_phase(error);
+        let mut root = self.root.clone();
+        root.end_time = now_nanos();
+        root.error = error.map(String::from);
+        self.spans.insert(0, root);
+    }
+
+    pub(crate) fn to_otlp_json(&self, service_name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": service_name } }
+                    ]
+                },
+                "scopeSpans": [{
+                    "scope": { "name": DEFAULT_SERVICE_NAME },
+                    "spans": self
+                        .spans
+                        .iter()
+                        .map(|span| span.to_otlp_json(&self.context.trace_id))
+                        .collect::<Vec<_>>(),
+                }]
+            }]
+        })
+    }
+}
+
+/*
+ * Spans exported as OTLP/HTTP JSON to a collector.
+ */
+pub(crate) struct OtlpExport {
+    body: serde_json::Value,
+}
+
+impl OtlpExport {
+    pub(crate) fn new(tracer: &Tracer, service_name: &str) -> Self {
+        OtlpExport {
+            body: tracer.to_otlp_json(service_name),
+        }
+    }
+
+    // the collector's base URL, or its full traces URL
+    pub(crate) fn generate_endpoint(collector_url: &str) -> String {
+        let collector_url = collector_url.trim_end_matches('/');
+        match collector_url.ends_with(TRACES_PATH) {
+            true => collector_url.to_string(),
+            false => format!("{collector_url}{TRACES_PATH}"),
+        }
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(&self, collector_url: &str) -> Result<Response, anyhow::Error> {
+        let client = waki::Client::new();
+        let response = client
+            .post(&Self::generate_endpoint(collector_url))
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_vec(&self.body)?)
+            .send()?;
+        Ok(response)
+    }
+
+    // export failures never fail the request
+    pub(crate) fn export(&self, collector_url: &str) {
+        match self.send(collector_url) {
+            Ok(response) if (200..300).contains(&response.status_code()) => {}
+            Ok(response) => eprintln!(
+                "Could not export spans: collector responded with status {}",
+                response.status_code()
+            ),
+            Err(e) => eprintln!("Could not export spans: {e}"),
+        }
+    }
+}
+
+fn random_id(bytes: usize) -> String {
+    uuid::Uuid::new_v4().as_bytes()[..bytes]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // spans received by the mock collector
+    pub(crate) static EXPORTED: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+
+    pub struct MockCollectorResponse;
+    impl MockCollectorResponse {
+        pub fn status_code(&self) -> u16 {
+            200
+        }
+    }
+
+    impl OtlpExport {
+        pub fn send(&self, _collector_url: &str) -> anyhow::Result<MockCollectorResponse> {
+            EXPORTED.lock().unwrap().push(self.body.clone());
+            Ok(MockCollectorResponse)
+        }
+    }
+
+    // the exported spans of a trace, by name
+    pub(crate) fn exported_spans(trace_id: &str) -> Vec<serde_json::Value> {
+        EXPORTED
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|body| {
+                body["resourceSpans"][0]["scopeSpans"][0]["spans"]
+                    .as_array()
+                    .cloned()
+            })
+            .flatten()
+            .filter(|span| span["traceId"] == trace_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_trace_context_from_traceparent() {
+        let context = TraceContext::from_traceparent(Some(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ));
+        assert_eq!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert_eq!(context.flags, "01");
+    }
+
+    #[test]
+    fn test_trace_context_new_trace() {
+        for traceparent in [
+            None,
+            Some("garbage"),
+            Some("00-00000000000000000000000000000000-00f067aa0ba902b7-01"),
+            Some("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+            Some("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01"),
+        ] {
+            let context = TraceContext::from_traceparent(traceparent);
+            assert_eq!(context.trace_id.len(), 32);
+            assert_ne!(context.trace_id, "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert!(context.parent_span_id.is_none());
+            assert_eq!(context.flags, "01");
+        }
+    }
+
+    #[test]
+    fn test_tracer_phases() {
+        let mut tracer = Tracer::new(
+            "POST /chat",
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        tracer.start_phase("parse_request", SpanKind::Internal);
+        tracer.start_phase("chat gpt-4o", SpanKind::Client);
+        tracer.set_attribute("gen_ai.request.model", "gpt-4o");
+        let traceparent = tracer.traceparent();
+        tracer.finish(None);
+
+        let names: Vec<&str> = tracer.spans.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["POST /chat", "parse_request", "chat gpt-4o"]);
+
+        let root = &tracer.spans[0];
+        assert_eq!(root.kind, SpanKind::Server);
+        assert_eq!(root.parent_span_id.as_deref(), Some("00f067aa0ba902b7"));
+        assert!(tracer.spans[1..]
+            .iter()
+            .all(|span| span.parent_span_id.as_ref() == Some(&root.span_id)));
+
+        let chat = &tracer.spans[2];
+        assert_eq!(
+            chat.attributes,
+            vec![(
+                "gen_ai.request.model".to_string(),
+                AttributeValue::from("gpt-4o")
+            )]
+        );
+        assert_eq!(
+            traceparent,
+            format!("00-4bf92f3577b34da6a3ce929d0e0e4736-{}-01", chat.span_id)
+        );
+        assert!(chat.end_time >= chat.start_time);
+    }
+
+    #[test]
+    fn test_tracer_calls() {
+        let mut tracer = Tracer::new("POST /chat", None);
+        tracer.start_phase("guardrails", SpanKind::Internal);
+        tracer.start_call("moderation");
+        tracer.set_attribute("gen_ai.system", "openai");
+        let traceparent = tracer.traceparent();
+        tracer.end_call(None);
+        tracer.set_attribute("phase", "guardrails");
+        tracer.finish(None);
+
+        let names: Vec<&str> = tracer.spans.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["POST /chat", "moderation", "guardrails"]);
+        let (moderation, guardrails) = (&tracer.spans[1], &tracer.spans[2]);
+        assert_eq!(moderation.kind, SpanKind::Client);
+        assert_eq!(
+            moderation.parent_span_id.as_ref(),
+            Some(&guardrails.span_id)
+        );
+        assert_eq!(moderation.attributes.len(), 1);
+        assert_eq!(guardrails.attributes.len(), 1);
+        assert!(traceparent.contains(&moderation.span_id));
+    }
+
+    #[test]
+    fn test_tracer_finish_with_error() {
+        let mut tracer = Tracer::new("POST /chat", None);
+        tracer.start_phase("guardrails", SpanKind::Internal);
+        tracer.finish(Some("Prompt injection detected"));
+        assert!(tracer
+            .spans
+            .iter()
+            .all(|span| span.error.as_deref() == Some("Prompt injection detected")));
+    }
+
+    #[test]
+    fn test_tracer_to_otlp_json() {
+        let mut tracer = Tracer::new("POST /chat", None);
+        tracer.start_phase("chat gpt-4o", SpanKind::Client);
+        tracer.set_attribute("gen_ai.usage.input_tokens", 42u64);
+        tracer.set_attribute("gen_ai.response.finish_reasons", vec!["stop".to_string()]);
+        tracer.finish(None);
+
+        let json = tracer.to_otlp_json("my-service");
+        let resource_spans = &json["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"][0]["value"]["stringValue"],
+            "my-service"
+        );
+        let spans = resource_spans["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["kind"], 2);
+        assert!(spans[0].get("parentSpanId").is_none());
+        assert_eq!(spans[1]["kind"], 3);
+        assert_eq!(spans[1]["parentSpanId"], spans[0]["spanId"]);
+        assert_eq!(spans[1]["status"]["code"], 1);
+        assert_eq!(
+            spans[1]["attributes"][0],
+            serde_json::json!({"key": "gen_ai.usage.input_tokens", "value": {"intValue": "42"}})
+        );
+        assert_eq!(
+            spans[1]["attributes"][1]["value"]["arrayValue"]["values"][0]["stringValue"],
+            "stop"
+        );
+    }
+
+    #[test]
+    fn test_otlp_generate_endpoint() {
+        assert_eq!(
+            OtlpExport::generate_endpoint("http://collector:4318/"),
+            "http://collector:4318/v1/traces"
+        );
+        assert_eq!(
+            OtlpExport::generate_endpoint("https://otlp.example.com/v1/traces"),
+            "https://otlp.example.com/v1/traces"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+#[cfg(not(test))]
+use waki::Response;
+
+#[cfg(not(test))]
+use crate::openai_payload::send_request;
+
+use crate::helpers::body::{IntoBody, Multipart, MultipartLimits};
+use crate::helpers::HttpError;
+use crate::openai_payload::generate_endpoint;
+
+pub(crate) const DEFAULT_TRANSCRIPTION_MODEL: &str = "whisper-1";
+// the upstream rejects larger files
+pub(crate) const DEFAULT_MAX_AUDIO_BYTES: usize = 25 * 1024 * 1024;
+const ENDPOINT: &str = "/v1/audio/transcriptions";
+// the file, the model and the optional fields below, with some leeway
+const MAX_PARTS: usize = 8;
+const FORWARDED_FIELDS: [&str; 3] = ["language", "prompt", "temperature"];
+
+/*
+ * The client's upload, rebuilt with only the fields the component forwards
+ * and a JSON response format, since the transcript text is read from it.
+ */
+#[derive(Debug, Clone)]
+pub(crate) struct TranscriptionRequest {
+    form: Multipart,
+}
+
+impl TranscriptionRequest {
+    pub(crate) fn from_request_body(
+        content_type: &str,
+        body: bytes::Bytes,
+        max_audio_bytes: usize,
+        allowed_model: &str,
+    ) -> Result<Self, anyhow::Error> {
+        let limits = MultipartLimits {
+            max_size: max_audio_bytes,
+            max_parts: MAX_PARTS,
+        };
+        let upload = Multipart::parse(content_type, body, &limits).map_err(|err| match err
+            .downcast::<HttpError>()
+        {
+            Ok(http_err) => http_err,
+            Err(err) => HttpError::bad_request(format!("Invalid request body: {err}")),
+        })?;
+
+        let file = upload
+            .part("file")
+            .filter(|file| file.filename.is_some() && !file.data.is_empty())
+            .ok_or_else(|| HttpError::bad_request("Missing 'file' field in request body"))?;
+        match upload.text("model") {
+            None | Some("") => {}
+            Some(model) if model == allowed_model => {}
+            Some(model) => {
+                return Err(HttpError::bad_request(format!("Model {model} is not allowed")).into())
+            }
+        }
+
+        let mut form = Multipart::new()
+            .with_part(file.clone())
+            .with_text("model", allowed_model)
+            .with_text("response_format", "json");
+        for field in FORWARDED_FIELDS {
+            if let Some(value) = upload.text(field).filter(|value| !value.is_empty()) {
+                form = form.with_text(field, value);
+            }
+        }
+        Ok(TranscriptionRequest { form })
+    }
+
+    pub(crate) fn generate_endpoint(&self, hostname: Option<String>) -> String {
+        generate_endpoint(hostname, ENDPOINT)
+    }
+
+    #[cfg(not(test))]
+    pub(crate) fn send(
+        &self,
+        hostname: Option<String>,
+        api_key: String,
+        forwarded_headers: &http::HeaderMap,
+    ) -> Result<Response, anyhow::Error> {
+        send_request(
+            waki::Method::Post,
+            &self.generate_endpoint(hostname),
+            &api_key,
+            forwarded_headers,
+            Some((
+                &self.form.content_type(),
+                self.form.clone().into_body()?.into(),
+            )),
+        )
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct TranscriptionResponse {
+    pub(crate) text: String,
+}
+
+impl TranscriptionResponse {
+    pub(crate) fn from_json_slice(response_body: &[u8]) -> Result<Self, anyhow::Error> {
+        serde_json::from_slice(response_body)
+            .map_err(|e| anyhow::anyhow!("Could not parse OpenAI transcription: {e}"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::helpers::body::MultipartPart;
+    use bytes::Bytes;
+
+    // Mock send method to avoid real HTTP call, transcribing the uploaded file as its file name,
+    // and rejecting the "upstream error" prompt
+    pub struct MockTranscriptionResponse {
+        status: u16,
+        headers: http::HeaderMap,
+        body: String,
+    }
+    impl MockTranscriptionResponse {
+        pub fn status_code(&self) -> u16 {
+            self.status
+        }
+        pub fn headers(&self) -> &http::HeaderMap {
+            &self.headers
+        }
+        pub fn body(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(self.body.clone().into())
+        }
+    }
+
+    impl TranscriptionRequest {
+        pub fn send(
+            &self,
+            _hostname: Option<String>,
+            _apikey: String,
+            _forwarded_headers: &http::HeaderMap,
+        ) -> anyhow::Result<MockTranscriptionResponse> {
+            let filename = self
+                .form
+                .part("file")
+                .and_then(|file| file.filename.clone())
+                .unwrap_or_default();
+            if self.form.text("prompt") == Some("upstream error") {
+                return Ok(MockTranscriptionResponse {
+                    status: 400,
+                    headers: http::HeaderMap::new(),
+                    body: "Bad Request".to_string(),
+                });
+            }
+            Ok(MockTranscriptionResponse {
+                status: 200,
+                headers: http::HeaderMap::new(),
+                body: serde_json::json!({ "text": format!("Transcript of {filename}") })
+                    .to_string(),
+            })
+        }
+    }
+
+    // an upload like browsers send it, with extra fields
+    pub(crate) fn upload(fields: &[(&str, &str)], audio: &'static [u8]) -> (String, Bytes) {
+        let form = fields
+            .iter()
+            .fold(Multipart::new(), |form, (name, value)| {
+                form.with_text(name, *value)
+            })
+            .with_part(MultipartPart {
+                name: "file".to_string(),
+                filename: Some("hello.webm".to_string()),
+                content_type: Some("audio/webm".to_string()),
+                data: Bytes::from_static(audio),
+            });
+        (form.content_type(), form.into_body().unwrap())
+    }
+
+    #[test]
+    fn test_transcription_request_from_request_body() {
+        let (content_type, body) = upload(
+            &[("language", "fr"), ("model", "whisper-1"), ("user", "42")],
+            b"audio",
+        );
+        let request =
+            TranscriptionRequest::from_request_body(&content_type, body, 1024, "whisper-1")
+                .unwrap();
+        assert_eq!(request.form.text("model"), Some("whisper-1"));
+        assert_eq!(request.form.text("response_format"), Some("json"));
+        assert_eq!(request.form.text("language"), Some("fr"));
+        assert!(request.form.part("user").is_none());
+        assert_eq!(
+            request.form.part("file").unwrap().data,
+            Bytes::from_static(b"audio")
+        );
+    }
+
+    #[test]
+    fn test_transcription_request_invalid() {
+        let (content_type, body) = upload(&[("model", "gpt-4o-transcribe")], b"audio");
+        let err = TranscriptionRequest::from_request_body(&content_type, body, 1024, "whisper-1")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Model gpt-4o-transcribe is not allowed");
+
+        let (content_type, body) = upload(&[], b"");
+        let err = TranscriptionRequest::from_request_body(&content_type, body, 1024, "whisper-1")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Missing 'file' field in request body");
+
+        let err = TranscriptionRequest::from_request_body(
+            "application/json",
+            Bytes::from("{}"),
+            1024,
+            "whisper-1",
+        )
+        .unwrap_err();
+        let http_err = err.downcast_ref::<HttpError>().unwrap();
+        assert_eq!(http_err.status_code, http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_transcription_request_too_large() {
+        let (content_type, body) = upload(&[], b"audio");
+        let err = TranscriptionRequest::from_request_body(&content_type, body, 16, "whisper-1")
+            .unwrap_err();
+        let http_err = err.downcast_ref::<HttpError>().unwrap();
+        assert_eq!(http_err.status_code, http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_transcription_generate_endpoint() {
+        let (content_type, body) = upload(&[], b"audio");
+        let request =
+            TranscriptionRequest::from_request_body(&content_type, body, 1024, "whisper-1")
+                .unwrap();
+        assert_eq!(
+            request.generate_endpoint(None),
+            "https://api.openai.com/v1/audio/transcriptions"
+        );
+    }
+}